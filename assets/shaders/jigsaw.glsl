@@ -17,9 +17,16 @@ void main() {
 
 #ifdef FRAGMENT_SHADER
 uniform sampler2D u_texture;
+uniform sampler2D u_back_texture;
+uniform float u_flipped;
 
 void main() {
-    vec4 texture_color = texture2D(u_texture, v_uv);
+    vec4 texture_color;
+    if (u_flipped > 0.5) {
+        texture_color = texture2D(u_back_texture, v_uv);
+    } else {
+        texture_color = texture2D(u_texture, v_uv);
+    }
     texture_color.a = 1.0;
     gl_FragColor = texture_color;
 }
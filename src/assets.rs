@@ -34,6 +34,16 @@ pub struct Sounds {
 #[derive(geng::Assets)]
 pub struct Sprites {
     pub table: ugli::Texture,
+    pub back: ugli::Texture,
+}
+
+/// Path to the puzzle image for headless code that can not load it as a texture.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn image_path(image: usize) -> std::path::PathBuf {
+    run_dir()
+        .join("assets")
+        .join("images")
+        .join(format!("{}.png", image + 1))
 }
 
 fn make_looped(sound: &mut geng::Sound) {
@@ -1,20 +1,45 @@
 use geng::Camera2d;
 
-use crate::jigsaw::Jigsaw;
+use crate::jigsaw::{Jigsaw, JigsawVertex, PuzzleGeometry};
 
 use super::*;
 
+use std::f32::consts::PI;
+
 const SNAP_DISTANCE: f32 = 0.2;
+/// Maximum orientation difference at which tiles still snap together.
+const SNAP_ANGLE: f32 = 0.2;
 const FOV_MIN: f32 = 2.0;
+/// Zooming out further is only allowed when the table does not fit otherwise.
 const FOV_MAX: f32 = 20.0;
+/// Height of the minimap relative to the screen.
+const MINIMAP_SIZE: f32 = 0.2;
+/// Keyboard panning speed, in screen heights per second.
+const PAN_SPEED: f32 = 1.0;
+/// Keyboard zooming speed, as the fov change per second relative to the fov.
+const ZOOM_SPEED: f32 = 1.5;
+/// Number of chat messages shown in the chat panel.
+const CHAT_VISIBLE: usize = 8;
+/// How long chat bubbles stay next to the cursors, in seconds.
+const CHAT_BUBBLE_TIME: f32 = 5.0;
+/// How long ping markers are shown, in seconds.
+const PING_TIME: f32 = 3.0;
+/// Free space kept on the table around every tile.
+const TABLE_MARGIN: f32 = 1.0;
 
 #[derive(HasId)]
 struct Player {
     id: Id,
     name: String,
     color: Rgba<f32>,
+    cursor: CursorStyle,
+    /// Team of the player, in team mode.
+    team: Option<usize>,
+    /// Last chat message and the time it was received.
+    bubble: Option<(String, f32)>,
     interpolation: Interpolated<Vec2<f32>>,
-    tile_grabbed: Option<(usize, Vec2<f32>)>,
+    /// Grabbed tile groups with their offsets from the cursor.
+    tiles_grabbed: Vec<(usize, Vec2<f32>)>,
 }
 
 struct Game {
@@ -33,13 +58,119 @@ struct Game {
     // intro_time: f32,
     time: f32,
     hovered_tile: Option<usize>,
+    /// Tile groups selected with a rubber band, one tile per group.
+    selection: HashSet<usize>,
+    edge_filter: EdgeFilter,
+    reference_view: ReferenceView,
+    trays: Vec<Tray>,
+    tray_tool: TrayTool,
+    /// Name given to the next tray, a default one when empty.
+    tray_name: String,
+    tray_name_typing: bool,
     customize: bool,
     name_typing: bool,
+    settings: Settings,
+    settings_open: bool,
+    /// Whether a settings slider takes the arrow keys.
+    slider_focused: bool,
+    /// Started once the music is first audible.
+    music: Option<geng::SoundEffect>,
+    chat: Vec<ChatMessage>,
+    chat_open: bool,
+    chat_input: String,
+    chat_typing: bool,
+    /// Messages received while the chat was closed.
+    chat_unread: usize,
+    pings: Vec<Ping>,
+    keymap: Keymap,
+    /// Action waiting for a key press to be bound to it.
+    rebinding: Option<Action>,
+    /// Action selected in the controls settings.
+    rebind_action: Action,
+    /// The tile grabbed most recently, to focus the camera on.
+    last_tile: Option<usize>,
     show_names: bool,
+    show_minimap: bool,
+    /// Local time the puzzle was finished at, to fade out the outlines.
     finish_time: Option<f32>,
+    progress: Progress,
+    /// Local time the progress was received at.
+    progress_time: f32,
+    /// Results sent by the server once the puzzle is finished.
+    stats: Option<PuzzleStats>,
+    show_stats: bool,
+    /// Progress of all racers, only sent in race mode.
+    leaderboard: Vec<RaceEntry>,
+    addr: String,
+    transition: Option<geng::Transition>,
     cursor_pos: Vec2<f64>,
     cursor_world: Vec2<f32>,
     touch: Option<Vec<geng::TouchPoint>>,
+    /// Two finger twist not yet applied to the grabbed tile.
+    twist: f32,
+}
+
+/// How non-edge pieces are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EdgeFilter {
+    All,
+    Highlight,
+    /// Hide the groups without any face up edge piece
+    EdgesOnly,
+}
+
+impl EdgeFilter {
+    fn next(self) -> Self {
+        match self {
+            Self::All => Self::Highlight,
+            Self::Highlight => Self::EdgesOnly,
+            Self::EdgesOnly => Self::All,
+        }
+    }
+}
+
+/// How the full picture is shown to the player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReferenceView {
+    Hidden,
+    /// The box lid in the corner of the screen
+    Corner,
+    /// Faint picture on the table where the puzzle is assembled
+    Ghost,
+}
+
+impl ReferenceView {
+    fn next(self) -> Self {
+        match self {
+            Self::Hidden => Self::Corner,
+            Self::Corner => Self::Ghost,
+            Self::Ghost => Self::Hidden,
+        }
+    }
+}
+
+/// What dragging over an empty part of the table does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrayTool {
+    Off,
+    Private,
+    Shared,
+}
+
+impl TrayTool {
+    fn next(self) -> Self {
+        match self {
+            Self::Off => Self::Private,
+            Self::Private => Self::Shared,
+            Self::Shared => Self::Off,
+        }
+    }
+}
+
+struct Ping {
+    player: Id,
+    pos: Vec2<f32>,
+    time: f32,
 }
 
 #[derive(Debug, Clone)]
@@ -50,26 +181,63 @@ struct Dragging {
 
 #[derive(Debug, Clone)]
 enum DragTarget {
-    Camera { initial_camera_pos: Vec2<f32> },
+    Camera {
+        initial_camera_pos: Vec2<f32>,
+    },
+    Selection {
+        start: Vec2<f32>,
+    },
+    NewTray {
+        start: Vec2<f32>,
+        private: bool,
+    },
+    /// Moving the bottom right corner of a tray
+    ResizeTray {
+        id: u64,
+    },
+    /// Moving the camera by pointing at the minimap
+    Minimap,
+}
+
+impl Player {
+    fn profile(&self) -> PlayerProfile {
+        PlayerProfile {
+            name: self.name.clone(),
+            color: self.color,
+            cursor: self.cursor,
+        }
+    }
 }
 
 impl Game {
     pub fn new(
         geng: &Geng,
         assets: &Rc<Assets>,
-        id: Id,
-        name: Option<String>,
-        room_config: RoomConfig,
-        tiles: Vec<TileState>,
+        setup: RoomSetup,
+        name: String,
+        addr: &str,
         mut connection: Connection,
     ) -> Self {
-        assets.sounds.music.play();
-        let image = &assets.images[room_config.image];
-        let size = image.size().map(|x| x as f32);
-        let size = size * 5.0 / size.y;
-        let seed = room_config.seed;
-        let mut jigsaw = Jigsaw::generate(geng.ugli(), seed, size, room_config.size);
-        let bounds = AABB::ZERO.extend_symmetric(size / 2.0).extend_uniform(3.0);
+        let RoomSetup {
+            player_id: id,
+            room_config,
+            tiles,
+            trays,
+            default_color,
+            chat,
+            stats,
+            progress,
+            team,
+        } = setup;
+        let geometry =
+            PuzzleGeometry::from_room_config(&room_config, assets.images[room_config.image].size())
+                .expect("Failed to generate jigsaw");
+        let size = geometry.size;
+        let mut jigsaw = Jigsaw::new(geng.ugli(), geometry, room_config.mode.copies());
+        let mut bounds = AABB::ZERO.extend_symmetric(size / 2.0).extend_uniform(3.0);
+        if let Some(spawned) = util::bounding_box(tiles.iter().map(|tile| tile.pos)) {
+            bounds = util::aabb_union(bounds, spawned.extend_uniform(TABLE_MARGIN));
+        }
         for (tile, state) in jigsaw.tiles.iter_mut().zip(tiles) {
             tile.grabbed_by = state.grabbed_by;
             tile.connected_to = state.connections;
@@ -77,18 +245,38 @@ impl Game {
             //     .teleport(tile.interpolated.get() - size / 2.0, Vec2::ZERO);
             // tile.interpolated.server_update(state.pos, Vec2::ZERO);
             tile.interpolated.teleport(state.pos, Vec2::ZERO);
+            tile.angle = state.angle;
+            tile.flipped = state.flipped;
         }
         let my_player = Player {
             id,
-            name: name.unwrap_or_else(|| batbox::preferences::load("name").unwrap_or_default()),
-            color: batbox::preferences::load("color").unwrap_or(Rgba::WHITE),
+            name,
+            color: batbox::preferences::load("color").unwrap_or(default_color),
+            cursor: batbox::preferences::load("cursor").unwrap_or_default(),
+            team,
+            bubble: None,
             interpolation: Interpolated::new(Vec2::ZERO, Vec2::ZERO),
-            tile_grabbed: None,
+            tiles_grabbed: Vec::new(),
         };
-        connection.send(ClientMessage::UpdateName(my_player.name.clone()));
+        connection.send(ClientMessage::UpdateProfile(my_player.profile()));
         Self {
             show_names: batbox::preferences::load("show_names").unwrap_or(true),
+            show_minimap: batbox::preferences::load("show_minimap").unwrap_or(true),
             name_typing: false,
+            settings: Settings::load(),
+            settings_open: false,
+            slider_focused: false,
+            music: None,
+            chat,
+            chat_open: false,
+            chat_input: String::new(),
+            chat_typing: false,
+            chat_unread: 0,
+            pings: Vec::new(),
+            keymap: Keymap::load(),
+            rebinding: None,
+            rebind_action: Action::ALL[0],
+            last_tile: None,
             customize: false,
             geng: geng.clone(),
             assets: assets.clone(),
@@ -107,26 +295,205 @@ impl Game {
             framebuffer_size: vec2(1, 1),
             dragging: None,
             hovered_tile: None,
+            selection: HashSet::new(),
+            edge_filter: EdgeFilter::All,
+            reference_view: ReferenceView::Hidden,
+            trays,
+            tray_tool: TrayTool::Off,
+            tray_name: String::new(),
+            tray_name_typing: false,
             play_connect_sound: false,
             bounds,
             jigsaw,
             room_config,
             // intro_time: 1.0,
             time: 0.0,
-            finish_time: None,
+            // Outlines of an already finished puzzle are not shown at all
+            finish_time: progress.finish_time.map(|_| -1.0),
+            progress,
+            progress_time: 0.0,
+            show_stats: stats.is_some(),
+            stats,
+            leaderboard: Vec::new(),
+            addr: addr.to_owned(),
+            transition: None,
             cursor_pos: Vec2::ZERO,
             cursor_world: Vec2::ZERO,
             touch: None,
+            twist: 0.0,
+        }
+    }
+    /// Whether the keyboard is taken by a focused ui element.
+    fn typing(&self) -> bool {
+        self.name_typing || self.chat_typing || self.tray_name_typing || self.slider_focused
+    }
+    fn play_sound(&self, sound: &geng::Sound) {
+        let volume = self.settings.effects();
+        if volume > 0.0 {
+            let mut effect = sound.effect();
+            effect.set_volume(volume);
+            effect.play();
+        }
+    }
+    fn update_music(&mut self) {
+        let volume = self.settings.music();
+        match &mut self.music {
+            Some(music) => music.set_volume(volume),
+            None if volume > 0.0 => {
+                let mut music = self.assets.sounds.music.effect();
+                music.set_volume(volume);
+                music.play();
+                self.music = Some(music);
+            }
+            None => {}
+        }
+    }
+    fn send_chat(&mut self) {
+        let text = std::mem::take(&mut self.chat_input);
+        if !text.trim().is_empty() {
+            self.connection.send(ClientMessage::Chat(text));
+        }
+    }
+    fn draw_chat(&self, framebuffer: &mut ugli::Framebuffer) {
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let size = framebuffer_size.y * 0.025;
+        let mut pos = framebuffer_size * vec2(1.0, 0.0) + vec2(-size, framebuffer_size.y * 0.1);
+        for message in self.chat.iter().rev().take(CHAT_VISIBLE) {
+            // Timestamps are sent in UTC
+            let time = chrono::TimeZone::timestamp_opt(&chrono::Local, message.timestamp as i64, 0)
+                .single()
+                .map_or_else(String::new, |time| time.format("%H:%M").to_string());
+            let color = self
+                .players
+                .get(&message.player)
+                .map_or(Rgba::WHITE, |player| player.color);
+            self.geng.default_font().draw_with_outline(
+                framebuffer,
+                &geng::PixelPerfectCamera,
+                &format!("[{}] {}: {}", time, message.name, message.text),
+                pos,
+                geng::TextAlign::RIGHT,
+                size,
+                color,
+                size * 0.05,
+                Rgba::BLACK,
+            );
+            pos.y += size * 1.2;
+        }
+    }
+    /// Seconds since the first move, stopped once the puzzle is finished.
+    fn elapsed_time(&self) -> f64 {
+        match self.progress.finish_time {
+            Some(time) => time,
+            None => self
+                .progress
+                .since_first_move
+                .map_or(0.0, |time| time + (self.time - self.progress_time) as f64),
+        }
+    }
+    /// Standings of the race, finished racers first.
+    fn draw_leaderboard(&self, framebuffer: &mut ugli::Framebuffer) {
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let size = framebuffer_size.y * 0.03;
+        let mut entries: Vec<&RaceEntry> = self.leaderboard.iter().collect();
+        entries.sort_by(
+            |a, b| match (a.progress.finish_time, b.progress.finish_time) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => b.progress.fraction().total_cmp(&a.progress.fraction()),
+            },
+        );
+        let mut pos = vec2(framebuffer_size.x - size, framebuffer_size.y * 0.6);
+        for (place, entry) in entries.into_iter().enumerate() {
+            let status = match entry.progress.finish_time {
+                Some(time) => util::format_duration(time),
+                None => format!("{:.0}%", entry.progress.fraction() * 100.0),
+            };
+            self.geng.default_font().draw_with_outline(
+                framebuffer,
+                &geng::PixelPerfectCamera,
+                &format!("{}. {} {}", place + 1, entry.name, status),
+                pos,
+                geng::TextAlign::RIGHT,
+                size,
+                entry.color,
+                size * 0.05,
+                Rgba::BLACK,
+            );
+            pos.y -= size * 1.2;
+        }
+    }
+    /// Summary shown once the puzzle is finished.
+    fn draw_stats(&self, framebuffer: &mut ugli::Framebuffer, stats: &PuzzleStats) {
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let size = framebuffer_size.y * 0.04;
+        let title = match stats.winning_team {
+            Some(team) => (format!("Team {} wins!", TEAMS[team].0), team_color(team)),
+            None => ("Puzzle complete!".to_owned(), Rgba::WHITE),
+        };
+        let mut lines = vec![
+            title,
+            (
+                format!("Time: {}", util::format_duration(stats.time)),
+                Rgba::WHITE,
+            ),
+        ];
+        for player in &stats.players {
+            let name = if player.name.is_empty() {
+                "anonymous"
+            } else {
+                &player.name
+            };
+            lines.push((
+                format!(
+                    "{}: {} pieces, best streak {}",
+                    name, player.connected, player.longest_streak
+                ),
+                player.color,
+            ));
+        }
+        let center = framebuffer_size / 2.0;
+        let half_size = vec2(size * 10.0, size * (lines.len() as f32 * 0.6 + 0.5));
+        self.geng.draw_2d(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Quad::new(
+                AABB::from_corners(center - half_size, center + half_size),
+                Rgba::new(0.0, 0.0, 0.0, 0.7),
+            ),
+        );
+        let mut pos = center + vec2(0.0, half_size.y - size * 1.2);
+        for (text, color) in lines {
+            self.geng.default_font().draw_with_outline(
+                framebuffer,
+                &geng::PixelPerfectCamera,
+                &text,
+                pos,
+                geng::TextAlign::CENTER,
+                size,
+                color,
+                size * 0.05,
+                Rgba::BLACK,
+            );
+            pos.y -= size * 1.2;
         }
     }
+    fn send_profile(&mut self) {
+        let profile = self.players.get(&self.id).unwrap().profile();
+        self.connection.send(ClientMessage::UpdateProfile(profile));
+    }
     fn get_player(&mut self, id: Id) -> &mut Player {
         if self.players.get(&id).is_none() {
             self.players.insert(Player {
                 id,
                 name: "".to_owned(),
                 color: Rgba::WHITE,
+                cursor: CursorStyle::Hand,
+                team: None,
+                bubble: None,
                 interpolation: Interpolated::new(Vec2::ZERO, Vec2::ZERO),
-                tile_grabbed: None,
+                tiles_grabbed: Vec::new(),
             });
         }
         self.players.get_mut(&id).unwrap()
@@ -134,11 +501,19 @@ impl Game {
     fn handle_connection(&mut self) {
         while let Some(message) = self.connection.try_recv() {
             match message {
-                ServerMessage::SetupId { .. } => unreachable!(),
+                ServerMessage::SetupId(..) => unreachable!(),
                 ServerMessage::RoomNotFound => unreachable!(),
+                ServerMessage::RoomFull => unreachable!(),
+                ServerMessage::InvalidRoomConfig => unreachable!(),
                 ServerMessage::RoomCreated(..) => unreachable!(),
-                ServerMessage::UpdatePlayerName(id, name) => {
-                    self.get_player(id).name = name;
+                ServerMessage::UpdatePlayerProfile(id, profile) => {
+                    let player = self.get_player(id);
+                    player.name = profile.name;
+                    player.color = profile.color;
+                    player.cursor = profile.cursor;
+                }
+                ServerMessage::TeamAssigned { player, team } => {
+                    self.get_player(player).team = Some(team);
                 }
                 ServerMessage::UpdatePos(id, pos) => {
                     self.get_player(id)
@@ -153,76 +528,499 @@ impl Game {
                     tile,
                     offset,
                 } => {
-                    self.players.get_mut(&player).unwrap().tile_grabbed = Some((tile, offset));
+                    self.get_player(player).tiles_grabbed.push((tile, offset));
                     self.jigsaw.tiles[tile].grabbed_by = Some(player);
                     for tile in self.jigsaw.get_all_connected(tile) {
                         self.jigsaw.tiles[tile].last_interaction_time = self.time;
                     }
                 }
+                ServerMessage::GrabDenied(tiles) => {
+                    // A newer grab may already be in progress, so only the denied tiles are dropped
+                    let player = self.players.get_mut(&self.id).unwrap();
+                    player
+                        .tiles_grabbed
+                        .retain(|(tile, _)| !tiles.contains(tile));
+                    for tile in tiles {
+                        if let Some(tile) = self.jigsaw.tiles.get_mut(tile) {
+                            if tile.grabbed_by == Some(self.id) {
+                                tile.grabbed_by = None;
+                            }
+                        }
+                    }
+                }
                 ServerMessage::TileReleased { player, tile, pos } => {
                     let player = self.get_player(player);
-                    let offset = player
-                        .tile_grabbed
-                        .take()
-                        .map_or(Vec2::ZERO, |(_, offset)| offset);
+                    player.tiles_grabbed.retain(|&(grabbed, _)| grabbed != tile);
                     let vel = Some(player.interpolation.get_derivative());
                     self.jigsaw.tiles[tile].grabbed_by = None;
                     self.move_tile(tile, self.jigsaw.tiles[tile].interpolated.get(), vel, true);
                     self.move_tile(tile, pos /*+ offset*/, None, false);
+                    // Tiles may be spread out further than the table was
+                    self.bounds = util::aabb_union(
+                        self.bounds,
+                        AABB::point(pos).extend_uniform(TABLE_MARGIN),
+                    );
+                }
+                ServerMessage::TileRotated {
+                    player,
+                    tile,
+                    angle,
+                } => {
+                    let delta = angle - self.jigsaw.tiles[tile].angle;
+                    for (grabbed, offset) in &mut self.get_player(player).tiles_grabbed {
+                        if *grabbed == tile {
+                            *offset = offset.rotate(delta);
+                        }
+                    }
+                    self.jigsaw.set_group_angle(tile, angle);
+                }
+                ServerMessage::TileFlipped { tile, flipped } => {
+                    self.jigsaw.tiles[tile].flipped = flipped;
+                    self.jigsaw.tiles[tile].last_interaction_time = self.time;
+                }
+                ServerMessage::TrayUpdated(tray) => {
+                    match self.trays.iter_mut().find(|other| other.id == tray.id) {
+                        Some(other) => *other = tray,
+                        None => self.trays.push(tray),
+                    }
+                }
+                ServerMessage::Chat(message) => {
+                    // Chat reaches racers on other boards, whose cursors are not shown
+                    if let Some(player) = self.players.get_mut(&message.player) {
+                        player.bubble = Some((message.text.clone(), self.time));
+                    }
+                    if !self.chat_open {
+                        self.chat_unread += 1;
+                    }
+                    self.chat.push(message);
+                }
+                ServerMessage::Progress(progress) => {
+                    if self.finish_time.is_none() && progress.finish_time.is_some() {
+                        self.finish_time = Some(self.time);
+                    }
+                    self.progress = progress;
+                    self.progress_time = self.time;
+                }
+                ServerMessage::Leaderboard(entries) => {
+                    self.leaderboard = entries;
+                }
+                ServerMessage::PuzzleFinished(stats) => {
+                    self.stats = Some(stats);
+                    self.show_stats = true;
+                }
+                // The browser loads the game again from the new url
+                #[cfg(target_arch = "wasm32")]
+                ServerMessage::MoveToRoom(room) => {
+                    web_sys::window()
+                        .unwrap()
+                        .location()
+                        .set_href(&format!("?room={}", room))
+                        .unwrap();
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                ServerMessage::MoveToRoom(room) => {
+                    let name = self.players.get(&self.id).unwrap().name.clone();
+                    let state = run(&self.geng, &self.addr, &room, Some(name));
+                    self.transition = Some(geng::Transition::Switch(Box::new(state)));
+                }
+                ServerMessage::Ping { player, pos } => {
+                    self.pings.push(Ping {
+                        player,
+                        pos,
+                        time: self.time,
+                    });
+                }
+                ServerMessage::TrayRemoved(id) => {
+                    self.trays.retain(|tray| tray.id != id);
                 }
                 ServerMessage::ConnectTiles(a, b) => {
                     self.jigsaw.tiles[a].connected_to.push(b);
                     self.jigsaw.tiles[b].connected_to.push(a);
-                    let delta = self.jigsaw.tiles[a].puzzle_pos.map(|x| x as i32)
-                        - self.jigsaw.tiles[b].puzzle_pos.map(|x| x as i32);
-                    let pos = if delta.x == 0 && delta.y.abs() == 1 {
-                        // Tile is adjacent vertically
-                        self.jigsaw.tiles[b].interpolated.get()
-                            + vec2(0.0, self.jigsaw.tile_size.y * delta.y.signum() as f32)
-                    } else if delta.y == 0 && delta.x.abs() == 1 {
-                        // Tile is adjacent horizontally
-                        self.jigsaw.tiles[b].interpolated.get()
-                            + vec2(self.jigsaw.tile_size.x * delta.x.signum() as f32, 0.0)
-                    } else {
-                        unreachable!()
-                    };
+                    let angle = self.jigsaw.tiles[b].angle;
+                    self.jigsaw.set_group_angle(a, angle);
+                    let pos = self
+                        .jigsaw
+                        .snap_position(a, b)
+                        .expect("Connected tiles are not adjacent");
                     self.move_tile(a, pos, None, true);
                     self.play_connect_sound = true;
                 }
             }
         }
     }
+    fn is_edge(&self, tile: usize) -> bool {
+        let tile = &self.jigsaw.tiles[tile];
+        !tile.flipped && tile.geometry.is_edge()
+    }
+    /// Tiles hidden by the edge filter.
+    fn hidden_tiles(&self) -> HashSet<usize> {
+        let mut hidden = HashSet::new();
+        if self.edge_filter != EdgeFilter::EdgesOnly {
+            return hidden;
+        }
+        let mut checked = HashSet::new();
+        for i in 0..self.jigsaw.tiles.len() {
+            if checked.contains(&i) {
+                continue;
+            }
+            let group = self.jigsaw.get_all_connected(i);
+            if !group
+                .iter()
+                .any(|&tile| self.is_edge(tile) || self.jigsaw.tiles[tile].grabbed_by.is_some())
+            {
+                hidden.extend(group.iter().copied());
+            }
+            checked.extend(group);
+        }
+        hidden
+    }
     fn hovered_tile(&self, pos: Vec2<f32>) -> Option<usize> {
+        let hidden = self.hidden_tiles();
         self.jigsaw
             .tiles
             .iter()
             .enumerate()
-            .filter(|(_, tile)| tile.contains(pos))
+            .filter(|(i, tile)| !hidden.contains(i) && self.can_touch(*i) && tile.contains(pos))
             .max_by_key(|(_, tile)| r32(tile.last_interaction_time))
             .map(|(i, _)| i)
     }
+    /// Whether the tile belongs to the copy of the player's team, if there are teams.
+    fn can_touch(&self, tile: usize) -> bool {
+        match self.players.get(&self.id).unwrap().team {
+            Some(team) => self.jigsaw.tiles[tile].copy == team,
+            None => true,
+        }
+    }
+    /// How much of the copy is assembled, from 0 to 1.
+    fn copy_fraction(&self, copy: usize) -> f32 {
+        let tiles: Vec<usize> = (0..self.jigsaw.tiles.len())
+            .filter(|&tile| self.jigsaw.tiles[tile].copy == copy)
+            .collect();
+        if tiles.len() <= 1 {
+            return 1.0;
+        }
+        let mut checked = HashSet::new();
+        let mut groups = 0;
+        for &tile in &tiles {
+            if checked.contains(&tile) {
+                continue;
+            }
+            checked.extend(self.jigsaw.get_all_connected(tile));
+            groups += 1;
+        }
+        (tiles.len() - groups) as f32 / (tiles.len() - 1) as f32
+    }
+    /// Table area where the copy is assembled.
+    fn copy_area(&self, copy: usize) -> AABB<f32> {
+        let size = self.jigsaw.tile_size * self.room_config.size.map(|x| x as f32);
+        let copies = self.room_config.mode.copies();
+        let center = jigsaw::copy_origin(size, copy, copies);
+        AABB {
+            x_min: if copy == 0 {
+                self.bounds.x_min
+            } else {
+                center.x - size.x
+            },
+            x_max: if copy + 1 == copies {
+                self.bounds.x_max
+            } else {
+                center.x + size.x
+            },
+            y_min: self.bounds.y_min,
+            y_max: self.bounds.y_max,
+        }
+    }
+    /// Progress of every team, in team mode.
+    fn draw_team_progress(&self, framebuffer: &mut ugli::Framebuffer) {
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let size = framebuffer_size.y * 0.03;
+        let mut pos = vec2(framebuffer_size.x - size, framebuffer_size.y * 0.6);
+        for (team, &(name, _)) in TEAMS.iter().enumerate() {
+            let players = self
+                .players
+                .iter()
+                .filter(|player| player.team == Some(team))
+                .count();
+            self.geng.default_font().draw_with_outline(
+                framebuffer,
+                &geng::PixelPerfectCamera,
+                &format!(
+                    "Team {} ({}) {:.0}%",
+                    name,
+                    players,
+                    self.copy_fraction(team) * 100.0
+                ),
+                pos,
+                geng::TextAlign::RIGHT,
+                size,
+                team_color(team),
+                size * 0.05,
+                Rgba::BLACK,
+            );
+            pos.y -= size * 1.2;
+        }
+    }
+    fn toggle_reference(&mut self) {
+        if !self.room_config.hide_reference {
+            self.reference_view = self.reference_view.next();
+        }
+    }
+    /// Screen area of the minimap, keeping the aspect ratio of the table.
+    fn minimap_area(&self) -> AABB<f32> {
+        const MARGIN: f32 = 10.0;
+        let height = self.framebuffer_size.y as f32 * MINIMAP_SIZE;
+        let width = height * self.bounds.width() / self.bounds.height();
+        AABB::point(vec2(MARGIN, MARGIN)).extend_positive(vec2(width, height))
+    }
+    fn world_to_minimap(&self, pos: Vec2<f32>) -> Vec2<f32> {
+        let area = self.minimap_area();
+        let t = (pos - self.bounds.bottom_left()) / self.bounds.size();
+        area.bottom_left() + t * area.size()
+    }
+    fn minimap_to_world(&self, pos: Vec2<f32>) -> Vec2<f32> {
+        let area = self.minimap_area();
+        let t = (pos - area.bottom_left()) / area.size();
+        self.bounds.bottom_left() + t * self.bounds.size()
+    }
+    /// Draws plain colored geometry.
+    fn draw_shape(
+        &self,
+        framebuffer: &mut ugli::Framebuffer,
+        camera: &impl geng::AbstractCamera2d,
+        mode: ugli::DrawMode,
+        vertices: Vec<Vec2<f32>>,
+        color: Rgba<f32>,
+    ) {
+        let vertices = vertices
+            .into_iter()
+            .map(|a_pos| JigsawVertex {
+                a_pos,
+                a_uv: Vec2::ZERO,
+            })
+            .collect();
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        ugli::draw(
+            framebuffer,
+            &self.assets.shaders.outline,
+            mode,
+            &ugli::VertexBuffer::new_dynamic(self.geng.ugli(), vertices),
+            (
+                ugli::uniforms! {
+                    u_model_matrix: Mat3::identity(),
+                    u_color: color,
+                    u_depth: 0.0,
+                },
+                geng::camera2d_uniforms(camera, framebuffer_size),
+            ),
+            ugli::DrawParameters {
+                blend_mode: Some(ugli::BlendMode::default()),
+                ..Default::default()
+            },
+        );
+    }
+    fn ping(&mut self) {
+        self.connection.send(ClientMessage::Ping(
+            self.cursor_world.clamp_aabb(self.bounds),
+        ));
+    }
+    /// Expanding rings at the pinged positions,
+    /// with arrows at the screen edge pointing to those off screen.
+    fn draw_pings(&self, framebuffer: &mut ugli::Framebuffer) {
+        const SEGMENTS: usize = 32;
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let view = AABB::ZERO.extend_positive(framebuffer_size);
+        let arrow_area = view.extend_uniform(-framebuffer_size.y * 0.05);
+        for ping in &self.pings {
+            let color = self
+                .players
+                .get(&ping.player)
+                .map_or(Rgba::WHITE, |player| player.color);
+            let t = (self.time - ping.time) / PING_TIME;
+            for wave in 0..3 {
+                let t = (t * 3.0 - wave as f32 * 0.3).fract().max(0.0);
+                let radius = self.camera.fov * 0.05 * t;
+                let ring = (0..SEGMENTS)
+                    .map(|i| {
+                        let angle = i as f32 / SEGMENTS as f32 * 2.0 * PI;
+                        ping.pos + vec2(angle.cos(), angle.sin()) * radius
+                    })
+                    .collect();
+                self.draw_shape(
+                    framebuffer,
+                    &self.camera,
+                    ugli::DrawMode::LineLoop { line_width: 2.0 },
+                    ring,
+                    Rgba {
+                        a: 1.0 - t,
+                        ..color
+                    },
+                );
+            }
+
+            let screen_pos = (ping.pos - self.camera.center) / self.camera.fov * framebuffer_size.y
+                + framebuffer_size / 2.0;
+            if !view.contains(screen_pos) {
+                let dir = (screen_pos - framebuffer_size / 2.0).normalize();
+                let pos = screen_pos.clamp_aabb(arrow_area);
+                let size = framebuffer_size.y * 0.02;
+                self.draw_shape(
+                    framebuffer,
+                    &geng::PixelPerfectCamera,
+                    ugli::DrawMode::Triangles,
+                    vec![
+                        pos + dir * size,
+                        pos - dir * size + dir.rotate_90() * size,
+                        pos - dir * size - dir.rotate_90() * size,
+                    ],
+                    color,
+                );
+            }
+        }
+    }
+    fn draw_minimap(&self, framebuffer: &mut ugli::Framebuffer) {
+        let area = self.minimap_area();
+        self.geng.draw_2d(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Quad::new(area, Rgba::new(0.0, 0.0, 0.0, 0.5)),
+        );
+        let quad = |aabb: AABB<f32>| {
+            [
+                aabb.bottom_left(),
+                aabb.bottom_right(),
+                aabb.top_right(),
+                aabb.bottom_left(),
+                aabb.top_right(),
+                aabb.top_left(),
+            ]
+        };
+
+        // Pieces, at least a pixel big
+        let scale = area.height() / self.bounds.height();
+        let piece_size = (self.jigsaw.tile_size * scale).map(|x| x.max(1.0));
+        let hidden = self.hidden_tiles();
+        let pieces = self
+            .jigsaw
+            .tiles
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !hidden.contains(i))
+            .flat_map(|(_, tile)| {
+                let pos = self.world_to_minimap(tile.interpolated.get());
+                quad(AABB::point(pos).extend_symmetric(piece_size / 2.0))
+            })
+            .collect();
+        self.draw_shape(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            ugli::DrawMode::Triangles,
+            pieces,
+            Rgba::new(0.8, 0.7, 0.5, 1.0),
+        );
+
+        for player in &self.players {
+            if player.id != self.id {
+                let pos = self.world_to_minimap(player.interpolation.get());
+                self.draw_shape(
+                    framebuffer,
+                    &geng::PixelPerfectCamera,
+                    ugli::DrawMode::Triangles,
+                    quad(AABB::point(pos).extend_uniform(2.0)).to_vec(),
+                    player.color,
+                );
+            }
+        }
+
+        let view = AABB::point(self.camera.center).extend_symmetric(
+            vec2(
+                self.camera.fov * self.framebuffer_size.x as f32 / self.framebuffer_size.y as f32,
+                self.camera.fov,
+            ) / 2.0,
+        );
+        let view = AABB::from_corners(
+            self.world_to_minimap(view.bottom_left()),
+            self.world_to_minimap(view.top_right()),
+        );
+        self.draw_shape(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            ugli::DrawMode::LineLoop { line_width: 1.0 },
+            vec![
+                view.bottom_left(),
+                view.bottom_right(),
+                view.top_right(),
+                view.top_left(),
+            ],
+            Rgba::WHITE,
+        );
+    }
+    fn draw_box_lid(&self, framebuffer: &mut ugli::Framebuffer) {
+        const MARGIN: f32 = 10.0;
+        let texture = &self.assets.images[self.room_config.image];
+        let framebuffer_size = framebuffer.size().map(|x| x as f32);
+        let size = texture.size().map(|x| x as f32);
+        let size = size * framebuffer_size.y * 0.25 / size.y;
+        let aabb = AABB::point(framebuffer_size - vec2(MARGIN, MARGIN))
+            .extend_left(size.x)
+            .extend_down(size.y);
+        self.geng.draw_2d(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Quad::new(aabb.extend_uniform(MARGIN / 2.0), Rgba::BLACK),
+        );
+        self.geng.draw_2d(
+            framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::TexturedQuad::new(aabb, texture),
+        );
+    }
     fn click(&mut self, screen_pos: Vec2<f64>) {
         self.update_cursor(screen_pos);
         let pos = self.camera.screen_to_world(
             self.framebuffer_size.map(|x| x as f32),
             screen_pos.map(|x| x as f32),
         );
-        if let Some(i) = self
+        let window = self.geng.window();
+        if self.show_minimap && self.minimap_area().contains(screen_pos.map(|x| x as f32)) {
+            self.start_drag(Dragging {
+                initial_screen_pos: screen_pos,
+                target: DragTarget::Minimap,
+            });
+            self.update_cursor(screen_pos);
+        } else if window.is_key_pressed(geng::Key::LShift)
+            || window.is_key_pressed(geng::Key::RShift)
+        {
+            self.start_drag(Dragging {
+                initial_screen_pos: screen_pos,
+                target: DragTarget::Selection { start: pos },
+            });
+        } else if let Some(id) = self.tray_handle(pos) {
+            self.start_drag(Dragging {
+                initial_screen_pos: screen_pos,
+                target: DragTarget::ResizeTray { id },
+            });
+        } else if let Some(i) = self
             .hovered_tile(pos)
             .filter(|_| self.finish_time.is_none())
         {
-            let tile = self.jigsaw.tiles.get_mut(i).unwrap();
-            let player = self.players.get_mut(&self.id).unwrap();
-            let offset = tile.interpolated.get() - pos;
-            player.tile_grabbed = Some((i, offset));
-            tile.grabbed_by = Some(self.id);
-            for tile in self.jigsaw.get_all_connected(i) {
-                self.jigsaw.tiles[tile].last_interaction_time = self.time;
-            }
-            self.assets.sounds.grab.play();
-            self.connection
-                .send(ClientMessage::GrabTile { tile: i, offset });
+            if self.selected_tiles().contains(&i) {
+                let tiles = self.selection.iter().copied().collect();
+                self.grab(tiles, pos);
+            } else {
+                self.selection.clear();
+                self.grab(vec![i], pos);
+            }
+        } else if self.tray_tool != TrayTool::Off {
+            self.selection.clear();
+            self.start_drag(Dragging {
+                initial_screen_pos: screen_pos,
+                target: DragTarget::NewTray {
+                    start: pos,
+                    private: self.tray_tool == TrayTool::Private,
+                },
+            });
         } else {
+            self.selection.clear();
             self.start_drag(Dragging {
                 initial_screen_pos: screen_pos,
                 target: DragTarget::Camera {
@@ -231,11 +1029,100 @@ impl Game {
             });
         }
     }
+    fn tray_handle_size(&self) -> f32 {
+        self.camera.fov * 0.02
+    }
+    /// The tray whose resize handle is at the position.
+    fn tray_handle(&self, pos: Vec2<f32>) -> Option<u64> {
+        let size = self.tray_handle_size();
+        self.trays
+            .iter()
+            .rev()
+            .filter(|tray| tray.can_edit(self.id))
+            .find(|tray| {
+                AABB::point(tray.area.bottom_right())
+                    .extend_uniform(size)
+                    .contains(pos)
+            })
+            .map(|tray| tray.id)
+    }
+    /// Removes the topmost tray under the cursor, if allowed.
+    fn remove_tray(&mut self) {
+        if let Some(tray) = self
+            .trays
+            .iter()
+            .rev()
+            .find(|tray| tray.can_edit(self.id) && tray.area.contains(self.cursor_world))
+        {
+            self.connection.send(ClientMessage::RemoveTray(tray.id));
+        }
+    }
+    /// Grabs the groups of given tiles, either all of them or none.
+    fn grab(&mut self, tiles: Vec<usize>, pos: Vec2<f32>) {
+        let groups: Vec<HashSet<usize>> = tiles
+            .iter()
+            .map(|&tile| self.jigsaw.get_all_connected(tile))
+            .collect();
+        if groups.iter().flatten().any(|&tile| {
+            let tile = &self.jigsaw.tiles[tile];
+            tile.grabbed_by.is_some()
+                || self
+                    .trays
+                    .iter()
+                    .any(|tray| tray.protects(self.id, tile.interpolated.get()))
+        }) {
+            return;
+        }
+        let grabbed: Vec<(usize, Vec2<f32>)> = tiles
+            .into_iter()
+            .map(|tile| (tile, self.jigsaw.tiles[tile].interpolated.get() - pos))
+            .collect();
+        for &(tile, _) in &grabbed {
+            self.jigsaw.tiles[tile].grabbed_by = Some(self.id);
+        }
+        for tile in groups.into_iter().flatten() {
+            self.jigsaw.tiles[tile].last_interaction_time = self.time;
+        }
+        self.last_tile = grabbed.first().map(|&(tile, _)| tile);
+        self.players.get_mut(&self.id).unwrap().tiles_grabbed = grabbed.clone();
+        self.play_sound(&self.assets.sounds.grab);
+        self.connection.send(ClientMessage::GrabTiles(grabbed));
+    }
+    /// All tiles of the selected groups.
+    fn selected_tiles(&self) -> HashSet<usize> {
+        self.selection
+            .iter()
+            .flat_map(|&tile| self.jigsaw.get_all_connected(tile))
+            .collect()
+    }
+    fn select_area(&mut self, area: AABB<f32>) {
+        self.selection.clear();
+        let mut checked = self.hidden_tiles();
+        for (i, tile) in self.jigsaw.tiles.iter().enumerate() {
+            if checked.contains(&i) || !self.can_touch(i) || !area.contains(tile.interpolated.get())
+            {
+                continue;
+            }
+            let group = self.jigsaw.get_all_connected(i);
+            if group
+                .iter()
+                .all(|&tile| self.jigsaw.tiles[tile].grabbed_by.is_none())
+            {
+                self.selection.insert(i);
+            }
+            checked.extend(group);
+        }
+    }
     fn release(&mut self) {
         self.stop_drag();
         let player = self.players.get_mut(&self.id).unwrap();
-        if let Some((tile_id, _)) = player.tile_grabbed.take() {
-            self.assets.sounds.grab.play();
+        let grabbed = std::mem::take(&mut player.tiles_grabbed);
+        if grabbed.is_empty() {
+            return;
+        }
+        self.play_sound(&self.assets.sounds.grab);
+        let mut updates = Vec::new();
+        for (tile_id, _) in grabbed {
             let connected = self.jigsaw.get_all_connected(tile_id);
             let tile = self.jigsaw.tiles.get_mut(tile_id).unwrap();
             tile.grabbed_by = None;
@@ -245,66 +1132,217 @@ impl Game {
             for &tile_id in &connected {
                 let tile = self.jigsaw.tiles.get(tile_id).unwrap();
                 let pos = tile.interpolated.get();
-                let puzzle_pos = tile.puzzle_pos;
                 for (i, other) in self.jigsaw.tiles.iter().enumerate() {
-                    if tile.connected_to.contains(&i) {
+                    // Face down tiles can not be connected
+                    if tile.connected_to.contains(&i)
+                        || tile.flipped
+                        || other.flipped
+                        || angle_distance(tile.angle, other.angle) > SNAP_ANGLE
+                    {
                         continue;
                     }
-                    let delta = puzzle_pos.map(|x| x as i32) - other.puzzle_pos.map(|x| x as i32);
-                    let delta = if delta.x == 0 && delta.y.abs() == 1 {
-                        // Tile is adjacent vertically
-                        Some(
-                            pos - other.interpolated.get()
-                                - vec2(0.0, self.jigsaw.tile_size.y * delta.y.signum() as f32),
-                        )
-                    } else if delta.y == 0 && delta.x.abs() == 1 {
-                        // Tile is adjacent horizontally
-                        Some(
-                            pos - other.interpolated.get()
-                                - vec2(self.jigsaw.tile_size.x * delta.x.signum() as f32, 0.0),
-                        )
-                    } else {
-                        None
-                    };
-                    if let Some(delta) = delta {
-                        // Delta to the snap position
-                        if delta.len() <= SNAP_DISTANCE {
+                    if let Some(snap_pos) = self.jigsaw.snap_position(tile_id, i) {
+                        if (pos - snap_pos).len() <= SNAP_DISTANCE {
                             self.connection
                                 .send(ClientMessage::ConnectTiles(tile_id, i));
-                            let pos = pos - delta;
-                            moves.push((tile_id, pos));
+                            moves.push((tile_id, snap_pos, other.angle));
                         }
                     }
                 }
             }
-            for (tile, pos) in moves {
+            for (tile, pos, angle) in moves {
+                self.jigsaw.set_group_angle(tile, angle);
                 self.move_tile(tile, pos, None, true);
             }
-            self.connection.send(ClientMessage::ReleaseTile(
+            updates.extend(
                 connected
                     .into_iter()
-                    .map(|tile| (tile, self.jigsaw.tiles[tile].interpolated.get()))
-                    .collect(),
-            ));
+                    .map(|tile| (tile, self.jigsaw.tiles[tile].interpolated.get())),
+            );
         }
+        self.connection.send(ClientMessage::ReleaseTile(updates));
     }
     fn move_tile(&mut self, tile: usize, pos: Vec2<f32>, vel: Option<Vec2<f32>>, snap: bool) {
         let vel = vel.unwrap_or(Vec2::ZERO);
         let tiles = self.jigsaw.get_all_connected(tile);
         let start_pos = self.jigsaw.tiles[tile].puzzle_pos.map(|x| x as i32);
+        let angle = self.jigsaw.tiles[tile].angle;
         for tile in tiles {
             let delta = self.jigsaw.tiles[tile].puzzle_pos.map(|x| x as i32) - start_pos;
+            let pos = pos + (delta.map(|x| x as f32) * self.jigsaw.tile_size).rotate(angle);
             if snap {
-                self.jigsaw.tiles[tile]
-                    .interpolated
-                    .teleport(pos + delta.map(|x| x as f32) * self.jigsaw.tile_size, vel);
+                self.jigsaw.tiles[tile].interpolated.teleport(pos, vel);
             } else {
-                self.jigsaw.tiles[tile]
-                    .interpolated
-                    .server_update(pos + delta.map(|x| x as f32) * self.jigsaw.tile_size, vel);
+                self.jigsaw.tiles[tile].interpolated.server_update(pos, vel);
             }
         }
     }
+    fn holding_tile(&self) -> bool {
+        !self.players.get(&self.id).unwrap().tiles_grabbed.is_empty()
+    }
+    /// Rotates the grabbed tile groups around the cursor.
+    fn rotate_grabbed(&mut self, delta: f32) {
+        let rotation = self.room_config.rotation;
+        if rotation == RotationMode::None {
+            return;
+        }
+        let player = self.players.get_mut(&self.id).unwrap();
+        for (tile, offset) in &mut player.tiles_grabbed {
+            let tile = *tile;
+            *offset = offset.rotate(delta);
+            let angle = rotation.round(normalize_angle(self.jigsaw.tiles[tile].angle + delta));
+            self.jigsaw.set_group_angle(tile, angle);
+            self.connection
+                .send(ClientMessage::RotateTile { tile, angle });
+        }
+    }
+    /// Turns over the grabbed tile, or the hovered one if nothing is grabbed.
+    fn flip(&mut self) {
+        if !self.room_config.double_sided {
+            return;
+        }
+        let tile = self
+            .players
+            .get(&self.id)
+            .unwrap()
+            .tiles_grabbed
+            .first()
+            .map(|&(tile, _)| tile)
+            .or(self.hovered_tile);
+        if let Some(tile) = tile {
+            // Connected tiles are always face up
+            if self.jigsaw.tiles[tile].connected_to.is_empty() {
+                self.connection.send(ClientMessage::FlipTile(tile));
+            }
+        }
+    }
+    fn twist(&mut self, delta: f32) {
+        match self.room_config.rotation {
+            RotationMode::None => {}
+            RotationMode::Quarter => {
+                // Only rotate once the fingers turned at least half way
+                let step = RotationMode::Quarter.step().unwrap();
+                self.twist += delta;
+                if self.twist.abs() >= step / 2.0 {
+                    let delta = step * self.twist.signum();
+                    self.twist -= delta;
+                    self.rotate_grabbed(delta);
+                }
+            }
+            RotationMode::Free => self.rotate_grabbed(delta),
+        }
+    }
+    /// Fov at which the whole table is visible.
+    fn fit_fov(&self) -> f32 {
+        let aspect = self.framebuffer_size.x as f32 / self.framebuffer_size.y.max(1) as f32;
+        self.bounds.height().max(self.bounds.width() / aspect)
+    }
+    fn clamp_fov(&self, fov: f32) -> f32 {
+        fov.clamp(FOV_MIN, FOV_MAX.max(self.fit_fov()))
+    }
+    fn zoom_to_fit(&mut self) {
+        self.camera.center = self.bounds.center();
+        self.camera.fov = self.clamp_fov(self.fit_fov());
+    }
+    fn focus_last_piece(&mut self) {
+        if let Some(tile) = self.last_tile {
+            self.camera.center = self.jigsaw.tiles[tile]
+                .interpolated
+                .get()
+                .clamp_aabb(self.bounds);
+        }
+    }
+    /// Continuous camera movement with the keyboard.
+    fn update_camera(&mut self, delta_time: f32) {
+        if self.typing() || self.rebinding.is_some() {
+            return;
+        }
+        let window = self.geng.window();
+        let pressed = |action| self.keymap.is_pressed(window, action);
+        let mut direction = Vec2::<f32>::ZERO;
+        if pressed(Action::PanUp) {
+            direction.y += 1.0;
+        }
+        if pressed(Action::PanDown) {
+            direction.y -= 1.0;
+        }
+        if pressed(Action::PanLeft) {
+            direction.x -= 1.0;
+        }
+        if pressed(Action::PanRight) {
+            direction.x += 1.0;
+        }
+        let mut zoom = 0.0;
+        if pressed(Action::ZoomIn) {
+            zoom -= 1.0;
+        }
+        if pressed(Action::ZoomOut) {
+            zoom += 1.0;
+        }
+        if direction != Vec2::ZERO {
+            let target = self.camera.center
+                + direction.normalize() * self.camera.fov * PAN_SPEED * delta_time;
+            self.camera.center = target.clamp_aabb(self.bounds);
+        }
+        if zoom != 0.0 {
+            self.camera.fov =
+                self.clamp_fov(self.camera.fov * (1.0 + zoom * ZOOM_SPEED * delta_time));
+        }
+    }
+    fn handle_action(&mut self, action: Action) {
+        match action {
+            Action::ZoomToFit => self.zoom_to_fit(),
+            Action::FocusLastPiece => self.focus_last_piece(),
+            Action::RotateLeft | Action::RotateRight => {
+                if let Some(step) = self.room_config.rotation.step() {
+                    self.rotate_grabbed(if action == Action::RotateLeft {
+                        step
+                    } else {
+                        -step
+                    });
+                }
+            }
+            Action::Flip => self.flip(),
+            Action::ToggleEdges => self.edge_filter = self.edge_filter.next(),
+            Action::GatherEdges => self.connection.send(ClientMessage::GatherEdges),
+            Action::ToggleBoxLid => self.toggle_reference(),
+            Action::TrayTool => self.tray_tool = self.tray_tool.next(),
+            Action::RemoveTray => self.remove_tray(),
+            Action::ClearSelection => self.selection.clear(),
+            Action::Ping => self.ping(),
+            Action::Undo => {
+                let window = self.geng.window();
+                if window.is_key_pressed(geng::Key::LCtrl)
+                    || window.is_key_pressed(geng::Key::RCtrl)
+                {
+                    // With shift, undo the last move of the hovered tile instead
+                    let tile = if window.is_key_pressed(geng::Key::LShift)
+                        || window.is_key_pressed(geng::Key::RShift)
+                    {
+                        self.hovered_tile
+                    } else {
+                        None
+                    };
+                    self.connection.send(ClientMessage::Undo(tile));
+                }
+            }
+            Action::ToggleMute => {
+                self.settings.toggle_mute();
+                self.settings.save();
+            }
+            Action::ToggleMinimap => {
+                self.show_minimap = !self.show_minimap;
+                batbox::preferences::save("show_minimap", &self.show_minimap);
+            }
+            // Handled every frame in `update_camera`
+            Action::PanUp
+            | Action::PanDown
+            | Action::PanLeft
+            | Action::PanRight
+            | Action::ZoomIn
+            | Action::ZoomOut => {}
+        }
+    }
     fn start_drag(&mut self, drag: Dragging) {
         self.stop_drag();
         self.dragging = Some(drag);
@@ -320,6 +1358,7 @@ impl Game {
         self.connection.send(ClientMessage::UpdatePos(clamped_pos));
         let me = self.get_player(self.id);
         me.interpolation.teleport(clamped_pos, Vec2::ZERO);
+        let minimap_pos = self.minimap_to_world(screen_pos.map(|x| x as f32));
 
         if let Some(dragging) = &mut self.dragging {
             self.hovered_tile = None;
@@ -332,6 +1371,21 @@ impl Game {
                     let target = initial_camera_pos + from - cursor_pos;
                     self.camera.center = target.clamp_aabb(self.bounds);
                 }
+                DragTarget::Selection { .. } | DragTarget::NewTray { .. } => {}
+                DragTarget::Minimap => {
+                    self.camera.center = minimap_pos.clamp_aabb(self.bounds);
+                }
+                DragTarget::ResizeTray { id } => {
+                    if let Some(tray) = self.trays.iter_mut().find(|tray| tray.id == id) {
+                        // Keep the top left corner in place
+                        let corner = vec2(tray.area.x_min, tray.area.y_max);
+                        let pos = vec2(
+                            cursor_pos.x.max(corner.x + Tray::MIN_SIZE),
+                            cursor_pos.y.min(corner.y - Tray::MIN_SIZE),
+                        );
+                        tray.area = AABB::from_corners(corner, pos);
+                    }
+                }
             }
         } else if let Some(hovered) = self.hovered_tile(clamped_pos) {
             if Some(hovered) != self.hovered_tile {
@@ -343,18 +1397,54 @@ impl Game {
         }
     }
     fn stop_drag(&mut self) {
-        if let Some(_dragging) = self.dragging.take() {}
+        if let Some(dragging) = self.dragging.take() {
+            match dragging.target {
+                DragTarget::Camera { .. } | DragTarget::Minimap => {}
+                DragTarget::Selection { start } => {
+                    self.select_area(AABB::from_corners(start, self.cursor_world));
+                }
+                DragTarget::NewTray { start, private } => {
+                    let area = AABB::from_corners(start, self.cursor_world);
+                    if area.width() >= Tray::MIN_SIZE && area.height() >= Tray::MIN_SIZE {
+                        let name = if !self.tray_name.trim().is_empty() {
+                            self.tray_name.trim().to_owned()
+                        } else if private {
+                            format!("{}'s tray", self.players.get(&self.id).unwrap().name)
+                        } else {
+                            "Shared tray".to_owned()
+                        };
+                        self.connection.send(ClientMessage::CreateTray {
+                            name,
+                            area,
+                            private,
+                        });
+                    }
+                }
+                DragTarget::ResizeTray { id } => {
+                    if let Some(tray) = self.trays.iter().find(|tray| tray.id == id) {
+                        self.connection.send(ClientMessage::ResizeTray {
+                            id,
+                            area: tray.area,
+                        });
+                    }
+                }
+            }
+        }
     }
     fn touch(&mut self, touches: Vec<geng::TouchPoint>) {
         match &touches[..] {
             [p] => self.click(p.position),
             [a, b] => {
-                self.start_drag(Dragging {
-                    initial_screen_pos: (a.position + b.position) / 2.0,
-                    target: DragTarget::Camera {
-                        initial_camera_pos: self.camera.center,
-                    },
-                });
+                // Second finger while holding a tile twists it instead of moving the camera
+                if !self.holding_tile() {
+                    self.start_drag(Dragging {
+                        initial_screen_pos: (a.position + b.position) / 2.0,
+                        target: DragTarget::Camera {
+                            initial_camera_pos: self.camera.center,
+                        },
+                    });
+                }
+                self.twist = 0.0;
                 self.touch = Some(touches);
             }
             _ => {}
@@ -365,15 +1455,23 @@ impl Game {
             [p] => self.update_cursor(p.position),
             [a, b] => {
                 if let Some([a0, b0]) = self.touch.as_deref() {
+                    if self.holding_tile() {
+                        let from = (b0.position - a0.position).arg() as f32;
+                        let to = (b.position - a.position).arg() as f32;
+                        let delta = normalize_angle(to - from + PI) - PI;
+                        self.touch = Some(touches);
+                        self.twist(delta);
+                        return;
+                    }
+
                     // Zoom
                     let d0 = (b0.position - a0.position).len() as f32;
                     let d = (b.position - a.position).len() as f32;
-                    self.camera.fov = (self.camera.fov / (d / d0)).clamp(FOV_MIN, FOV_MAX);
+                    self.camera.fov = self.clamp_fov(self.camera.fov / (d / d0));
 
                     // Move
                     if let Some(drag) = &self.dragging {
-                        let DragTarget::Camera { initial_camera_pos } = drag.target;
-                        {
+                        if let DragTarget::Camera { initial_camera_pos } = drag.target {
                             let p0 = drag.initial_screen_pos.map(|x| x as f32);
                             let p1 = (a.position + b.position).map(|x| x as f32) / 2.0;
                             let from = self
@@ -395,21 +1493,155 @@ impl Game {
 }
 
 impl geng::State for Game {
+    fn transition(&mut self) -> Option<geng::Transition> {
+        self.transition.take()
+    }
     fn ui<'a>(&'a mut self, cx: &'a geng::ui::Controller) -> Box<dyn geng::ui::Widget + 'a> {
         use geng::ui::*;
         self.name_typing = false;
+        self.chat_typing = false;
+        self.tray_name_typing = false;
+        self.slider_focused = false;
+        if self.settings_open {
+            let percent = |value: f64| format!("{:.0}%", value * 100.0);
+            let settings = &mut self.settings;
+            let music_volume = Slider::new(
+                cx,
+                "Music volume".to_owned(),
+                settings.music_volume,
+                0.0..=1.0,
+            )
+            .display(percent);
+            if let Some(value) = music_volume.get_change() {
+                settings.music_volume = value;
+            }
+            let mute_music = Button::new(
+                cx,
+                if settings.music_muted {
+                    "unmute"
+                } else {
+                    "mute"
+                },
+            );
+            if mute_music.was_clicked() {
+                settings.music_muted = !settings.music_muted;
+            }
+            let effects_volume = Slider::new(
+                cx,
+                "Effects volume".to_owned(),
+                settings.effects_volume,
+                0.0..=1.0,
+            )
+            .display(percent);
+            if let Some(value) = effects_volume.get_change() {
+                settings.effects_volume = value;
+            }
+            let mute_effects = Button::new(
+                cx,
+                if settings.effects_muted {
+                    "unmute"
+                } else {
+                    "mute"
+                },
+            );
+            if mute_effects.was_clicked() {
+                settings.effects_muted = !settings.effects_muted;
+            }
+            let cursor_size = Slider::new(
+                cx,
+                "Cursor size".to_owned(),
+                settings.cursor_size as f64,
+                0.5..=3.0,
+            )
+            .display(percent);
+            if let Some(value) = cursor_size.get_change() {
+                settings.cursor_size = value as f32;
+            }
+            let name_size = Slider::new(
+                cx,
+                "Name size".to_owned(),
+                settings.name_size as f64,
+                0.5..=3.0,
+            )
+            .display(percent);
+            if let Some(value) = name_size.get_change() {
+                settings.name_size = value as f32;
+            }
+            let zoom_sensitivity = Slider::new(
+                cx,
+                "Zoom sensitivity".to_owned(),
+                settings.zoom_sensitivity as f64,
+                0.2..=3.0,
+            )
+            .display(percent);
+            if let Some(value) = zoom_sensitivity.get_change() {
+                settings.zoom_sensitivity = value as f32;
+            }
+            self.slider_focused = *music_volume.focused
+                || *effects_volume.focused
+                || *cursor_size.focused
+                || *name_size.focused
+                || *zoom_sensitivity.focused;
+            let done_button = Button::new(cx, "done");
+            if done_button.was_clicked() {
+                self.settings_open = false;
+                settings.save();
+            }
+            return (
+                (music_volume, mute_music).row(),
+                (effects_volume, mute_effects).row(),
+                cursor_size,
+                name_size,
+                zoom_sensitivity,
+                done_button.center(),
+            )
+                .column()
+                .center()
+                .boxed();
+        }
         if self.customize {
             let save_button = Button::new(cx, "save");
             if save_button.was_clicked() {
                 self.customize = false;
-                batbox::preferences::save("name", &self.players.get(&self.id).unwrap().name);
+                let me = self.players.get(&self.id).unwrap();
+                batbox::preferences::save("name", &me.name);
+                batbox::preferences::save("color", &me.color);
+                batbox::preferences::save("cursor", &me.cursor);
                 batbox::preferences::save("show_names", &self.show_names);
-                self.connection.send(ClientMessage::UpdateName(
-                    self.players.get(&self.id).unwrap().name.clone(),
-                ));
+                self.send_profile();
+            }
+            let me = self.players.get_mut(&self.id).unwrap();
+            let color_index = (0..PLAYER_COLORS.len()).find(|&i| player_color(i) == me.color);
+            let color_button = Button::new(
+                cx,
+                &format!(
+                    "Color: {}",
+                    color_index.map_or("custom", |i| PLAYER_COLORS[i].0)
+                ),
+            );
+            if color_button.was_clicked() {
+                me.color = player_color(color_index.map_or(0, |i| i + 1));
             }
-            let name_input =
-                TextInput::new(cx, &mut self.players.get_mut(&self.id).unwrap().name, 15);
+            let cursor_button = Button::new(
+                cx,
+                match me.cursor {
+                    CursorStyle::Hand => "Cursor: hand",
+                    CursorStyle::Dot => "Cursor: dot",
+                },
+            );
+            if cursor_button.was_clicked() {
+                me.cursor = match me.cursor {
+                    CursorStyle::Hand => CursorStyle::Dot,
+                    CursorStyle::Dot => CursorStyle::Hand,
+                };
+            }
+            let name_input = TextInput::new(
+                cx,
+                &self.geng,
+                &mut self.players.get_mut(&self.id).unwrap().name,
+                NAME_MAX,
+            )
+            .placeholder("click to change your name");
             self.name_typing = *name_input.capture;
             let show_names = Button::new(
                 cx,
@@ -419,12 +1651,45 @@ impl geng::State for Game {
                     "Show names: NO"
                 },
             );
+            let rebind_action = self.rebind_action;
+            let action_button = Button::new(
+                cx,
+                &format!("{:?}: {:?}", rebind_action, self.keymap.keys(rebind_action)),
+            );
+            if action_button.was_clicked() {
+                let index = Action::ALL
+                    .iter()
+                    .position(|&action| action == rebind_action)
+                    .unwrap();
+                self.rebind_action = Action::ALL[(index + 1) % Action::ALL.len()];
+            }
+            let rebind_button = Button::new(
+                cx,
+                if self.rebinding.is_some() {
+                    "press a key..."
+                } else {
+                    "rebind"
+                },
+            );
+            if rebind_button.was_clicked() {
+                self.rebinding = Some(rebind_action);
+            }
+            let reset_controls = Button::new(cx, "reset controls");
+            if reset_controls.was_clicked() {
+                self.keymap = Keymap::default();
+                self.keymap.save();
+            }
             if show_names.was_clicked() {
                 self.show_names = !self.show_names;
             }
             (
                 name_input.center(),
+                color_button.center(),
+                cursor_button.center(),
                 show_names.center(),
+                action_button.center(),
+                rebind_button.center(),
+                reset_controls.center(),
                 save_button.center(),
             )
                 .column()
@@ -435,39 +1700,161 @@ impl geng::State for Game {
             if customize_button.was_clicked() {
                 self.customize = true;
             }
-            (customize_button.align(vec2(0.0, 1.0)),).stack().boxed()
+            let settings_button = Button::new(cx, "settings");
+            if settings_button.was_clicked() {
+                self.settings_open = true;
+            }
+            let edges_button = Button::new(
+                cx,
+                match self.edge_filter {
+                    EdgeFilter::All => "Edges: -",
+                    EdgeFilter::Highlight => "Edges: highlight",
+                    EdgeFilter::EdgesOnly => "Edges: only",
+                },
+            );
+            if edges_button.was_clicked() {
+                self.edge_filter = self.edge_filter.next();
+            }
+            let gather_button = Button::new(cx, "gather edges");
+            if gather_button.was_clicked() {
+                self.connection.send(ClientMessage::GatherEdges);
+            }
+            let spread_button = Button::new(cx, "spread");
+            if spread_button.was_clicked() {
+                self.connection.send(ClientMessage::SpreadPieces);
+            }
+            let reference_button = Button::new(
+                cx,
+                match self.reference_view {
+                    _ if self.room_config.hide_reference => "Box lid: disabled",
+                    ReferenceView::Hidden => "Box lid: -",
+                    ReferenceView::Corner => "Box lid: corner",
+                    ReferenceView::Ghost => "Box lid: table",
+                },
+            );
+            if reference_button.was_clicked() {
+                self.toggle_reference();
+            }
+            let tray_button = Button::new(
+                cx,
+                match self.tray_tool {
+                    TrayTool::Off => "New trays: -",
+                    TrayTool::Private => "New trays: private",
+                    TrayTool::Shared => "New trays: shared",
+                },
+            );
+            if tray_button.was_clicked() {
+                self.tray_tool = self.tray_tool.next();
+            }
+            let chat_button = Button::new(
+                cx,
+                &if self.chat_unread > 0 {
+                    format!("chat ({})", self.chat_unread)
+                } else {
+                    "chat".to_owned()
+                },
+            );
+            if chat_button.was_clicked() {
+                self.chat_open = !self.chat_open;
+                self.chat_unread = 0;
+            }
+            let buttons = (
+                customize_button,
+                settings_button,
+                edges_button,
+                gather_button,
+                spread_button,
+                reference_button,
+                tray_button,
+                chat_button,
+            )
+                .column()
+                .align(vec2(0.0, 1.0));
+            // Teams follow the progress of their own copy
+            let fraction = match self.players.get(&self.id).unwrap().team {
+                Some(team) => self.copy_fraction(team),
+                None => self.progress.fraction(),
+            };
+            let progress_bar = ProgressBar::new(
+                cx,
+                format!(
+                    "{}  {:.0}%",
+                    util::format_duration(self.elapsed_time()),
+                    fraction * 100.0
+                ),
+                fraction,
+            );
+            // Closed results can be opened again from under the progress bar
+            let mut top = progress_bar.boxed();
+            if self.stats.is_some() && !self.show_stats {
+                let results_button = Button::new(cx, "results");
+                if results_button.was_clicked() {
+                    self.show_stats = true;
+                }
+                top = (top, results_button.center()).column().boxed();
+            }
+            let mut widgets = (buttons, top.align(vec2(0.5, 1.0))).stack().boxed();
+            if self.chat_open {
+                let chat_input =
+                    TextInput::new(cx, &self.geng, &mut self.chat_input, CHAT_MESSAGE_MAX)
+                        .placeholder("click to chat");
+                self.chat_typing = *chat_input.capture;
+                widgets = (widgets, chat_input.align(vec2(1.0, 0.0))).stack().boxed();
+            }
+            if self.tray_tool != TrayTool::Off {
+                let tray_name_input =
+                    TextInput::new(cx, &self.geng, &mut self.tray_name, TRAY_NAME_MAX)
+                        .placeholder("tray name");
+                self.tray_name_typing = *tray_name_input.capture;
+                widgets = (widgets, tray_name_input.align(vec2(0.0, 0.0)))
+                    .stack()
+                    .boxed();
+            }
+            if self.stats.is_some() && self.show_stats {
+                let new_puzzle_button = Button::new(cx, "new puzzle");
+                if new_puzzle_button.was_clicked() {
+                    self.connection.send(ClientMessage::NewPuzzle);
+                }
+                let close_button = Button::new(cx, "close");
+                if close_button.was_clicked() {
+                    self.show_stats = false;
+                }
+                let results = (new_puzzle_button, close_button)
+                    .row()
+                    .align(vec2(0.5, 0.2));
+                widgets = (widgets, results).stack().boxed();
+            }
+            widgets
         }
     }
     fn update(&mut self, delta_time: f64) {
         let delta_time = delta_time as f32;
         self.time += delta_time;
 
-        if self.finish_time.is_none()
-            && self.jigsaw.get_all_connected(0).len() == self.jigsaw.tiles.len()
-        {
-            self.finish_time = Some(self.time);
-        }
-
         self.handle_connection();
+        self.update_music();
+        let time = self.time;
+        self.pings.retain(|ping| time - ping.time < PING_TIME);
+        self.update_camera(delta_time);
 
         if std::mem::take(&mut self.play_connect_sound) {
-            self.assets.sounds.connect_piece.play();
+            self.play_sound(&self.assets.sounds.connect_piece);
         }
 
         let mut moves = Vec::new();
         for player in &mut self.players {
             player.interpolation.update(delta_time);
 
-            // Update grabbed tile
-            if let Some((tile_id, offset)) = player.tile_grabbed {
-                if let Some(tile) = self.jigsaw.tiles.get_mut(tile_id) {
-                    if tile.grabbed_by != Some(player.id) {
-                        player.tile_grabbed = None;
-                    } else {
-                        moves.push((tile_id, player.interpolation.get() + offset));
-                    }
+            // Update grabbed tiles
+            let pos = player.interpolation.get();
+            let tiles = &self.jigsaw.tiles;
+            player.tiles_grabbed.retain(|&(tile_id, offset)| {
+                if tiles[tile_id].grabbed_by != Some(player.id) {
+                    return false;
                 }
-            }
+                moves.push((tile_id, pos + offset));
+                true
+            });
         }
         for (tile, pos) in moves {
             self.move_tile(tile, pos, None, true);
@@ -490,7 +1877,7 @@ impl geng::State for Game {
             None,
         );
 
-        if self.customize {
+        if self.customize || self.settings_open {
             self.geng
                 .window()
                 .set_cursor_type(geng::CursorType::Default);
@@ -522,11 +1909,80 @@ impl geng::State for Game {
             &self.camera,
             &draw_2d::Quad::new(self.bounds, Rgba::new(0.1, 0.1, 0.1, 0.1)),
         );
+        if self.room_config.mode == GameMode::Teams {
+            for team in 0..TEAMS.len() {
+                self.geng.draw_2d(
+                    framebuffer,
+                    &self.camera,
+                    &draw_2d::Quad::new(
+                        self.copy_area(team),
+                        Rgba {
+                            a: 0.1,
+                            ..team_color(team)
+                        },
+                    ),
+                );
+            }
+        }
+
+        for tray in &self.trays {
+            let color = tray
+                .owner
+                .and_then(|owner| self.players.get(&owner))
+                .map_or(Rgba::new(0.8, 0.8, 0.8, 1.0), |player| player.color);
+            self.geng.draw_2d(
+                framebuffer,
+                &self.camera,
+                &draw_2d::Quad::new(tray.area, Rgba { a: 0.15, ..color }),
+            );
+            if tray.can_edit(self.id) {
+                self.geng.draw_2d(
+                    framebuffer,
+                    &self.camera,
+                    &draw_2d::Quad::new(
+                        AABB::point(tray.area.bottom_right())
+                            .extend_uniform(self.tray_handle_size()),
+                        Rgba { a: 0.5, ..color },
+                    ),
+                );
+            }
+            let size = self.camera.fov * 0.02;
+            self.geng.default_font().draw_with_outline(
+                framebuffer,
+                &self.camera,
+                &tray.name,
+                tray.area.top_left() + vec2(size * 0.5, -size * 1.5),
+                geng::TextAlign::LEFT,
+                size,
+                color,
+                size * 0.05,
+                Rgba::BLACK,
+            );
+        }
+
+        if self.reference_view == ReferenceView::Ghost {
+            let size = self.jigsaw.tile_size * self.room_config.size.map(|x| x as f32);
+            let copies = self.room_config.mode.copies();
+            for copy in 0..copies {
+                self.geng.draw_2d(
+                    framebuffer,
+                    &self.camera,
+                    &draw_2d::TexturedQuad::colored(
+                        AABB::point(jigsaw::copy_origin(size, copy, copies))
+                            .extend_symmetric(size / 2.0),
+                        &self.assets.images[self.room_config.image],
+                        Rgba::new(1.0, 1.0, 1.0, 0.2),
+                    ),
+                );
+            }
+        }
 
         ugli::clear(framebuffer, None, Some(1.0), None);
 
         let mut tiles: Vec<_> = self.jigsaw.tiles.iter().enumerate().collect();
         tiles.sort_by_key(|(_, tile)| r32(tile.last_interaction_time));
+        let hidden = self.hidden_tiles();
+        tiles.retain(|(i, _)| !hidden.contains(i));
         let mut grabbed_tiles = HashMap::new();
         for (i, tile) in self.jigsaw.tiles.iter().enumerate() {
             if tile.grabbed_by.is_some() {
@@ -536,8 +1992,8 @@ impl geng::State for Game {
             }
         }
 
-        // Combine all meshes into 1
-        {
+        // Combine all meshes into 1 per side
+        for flipped in [false, true] {
             #[derive(ugli::Vertex)]
             struct Vertex {
                 a_pos: Vec3<f32>,
@@ -546,6 +2002,7 @@ impl geng::State for Game {
             let mesh: Vec<Vertex> = tiles
                 .iter()
                 .enumerate()
+                .filter(|(_, (_, tile))| tile.flipped == flipped)
                 .flat_map(|(depth_i, (i, tile))| {
                     let mut matrix = tile.matrix();
                     if let Some(connected_to) = grabbed_tiles.get(i) {
@@ -557,7 +2014,7 @@ impl geng::State for Game {
                             * Mat3::translate(delta);
                     }
                     let depth = 1.0 - 2.0 * (depth_i as f32 + 0.5) / tiles.len() as f32;
-                    tile.mesh.iter().map(move |v| {
+                    tile.geometry.triangles.iter().flatten().map(move |v| {
                         let pos = matrix * v.a_pos.extend(1.0);
                         let a_pos = (pos.xy() / pos.z).extend(depth);
                         Vertex {
@@ -567,6 +2024,9 @@ impl geng::State for Game {
                     })
                 })
                 .collect();
+            if mesh.is_empty() {
+                continue;
+            }
             let mesh = ugli::VertexBuffer::new_dynamic(self.geng.ugli(), mesh);
             ugli::draw(
                 framebuffer,
@@ -577,6 +2037,8 @@ impl geng::State for Game {
                     ugli::uniforms! {
                         u_model_matrix: Mat3::identity(),
                         u_texture: &self.assets.images[self.room_config.image],
+                        u_back_texture: &self.assets.sprites.back,
+                        u_flipped: if flipped { 1.0f32 } else { 0.0 },
                     },
                     geng::camera2d_uniforms(&self.camera, framebuffer.size().map(|x| x as f32)),
                 ),
@@ -592,6 +2054,7 @@ impl geng::State for Game {
             .hovered_tile
             .map(|tile| self.jigsaw.get_all_connected(tile))
             .unwrap_or_default();
+        let selected = self.selected_tiles();
 
         for (depth_i, (i, tile)) in tiles.iter().enumerate() {
             let mut matrix = tile.matrix();
@@ -603,6 +2066,10 @@ impl geng::State for Game {
             }
             let mut outline_color = if hovered.contains(i) {
                 Rgba::WHITE
+            } else if selected.contains(i) {
+                Rgba::new(1.0, 1.0, 0.0, 1.0)
+            } else if self.edge_filter == EdgeFilter::Highlight && self.is_edge(*i) {
+                Rgba::new(0.3, 0.8, 1.0, 1.0)
             } else {
                 Rgba::BLACK
             };
@@ -631,53 +2098,118 @@ impl geng::State for Game {
             );
         }
 
-        for player in &self.players {
-            let size = self.camera.fov * 0.01;
-            let texture = if player.tile_grabbed.is_some()
-                || player.id == self.id && self.dragging.is_some()
-            {
-                &self.assets.hand.grab
-            } else if self.finish_time.is_some() {
-                &self.assets.hand.thumb
-            } else {
-                &self.assets.hand.regular
-            };
+        if let Some(Dragging {
+            target: DragTarget::Selection { start } | DragTarget::NewTray { start, .. },
+            ..
+        }) = self.dragging
+        {
             self.geng.draw_2d(
                 framebuffer,
                 &self.camera,
-                &draw_2d::TexturedQuad::unit(texture)
-                    .scale_uniform(size)
-                    .translate(player.interpolation.get()),
+                &draw_2d::Quad::new(
+                    AABB::from_corners(start, self.cursor_world),
+                    Rgba::new(1.0, 1.0, 0.0, 0.2),
+                ),
             );
+        }
+
+        for player in &self.players {
+            let size = self.camera.fov * 0.01 * self.settings.cursor_size;
+            let name_size = self.camera.fov * 0.02 * self.settings.name_size;
+            let grabbing =
+                !player.tiles_grabbed.is_empty() || player.id == self.id && self.dragging.is_some();
+            match player.cursor {
+                CursorStyle::Hand => {
+                    let texture = if grabbing {
+                        &self.assets.hand.grab
+                    } else if self.finish_time.is_some() {
+                        &self.assets.hand.thumb
+                    } else {
+                        &self.assets.hand.regular
+                    };
+                    self.geng.draw_2d(
+                        framebuffer,
+                        &self.camera,
+                        &draw_2d::TexturedQuad::unit(texture)
+                            .scale_uniform(size)
+                            .translate(player.interpolation.get()),
+                    );
+                }
+                CursorStyle::Dot => {
+                    let radius = if grabbing { size * 0.3 } else { size * 0.5 };
+                    self.geng.draw_2d(
+                        framebuffer,
+                        &self.camera,
+                        &draw_2d::Ellipse::circle(player.interpolation.get(), radius, Rgba::BLACK),
+                    );
+                    self.geng.draw_2d(
+                        framebuffer,
+                        &self.camera,
+                        &draw_2d::Ellipse::circle(
+                            player.interpolation.get(),
+                            radius * 0.8,
+                            player.color,
+                        ),
+                    );
+                }
+            }
             if self.show_names {
                 self.geng.default_font().draw_with_outline(
                     framebuffer,
                     &self.camera,
                     &player.name,
-                    player.interpolation.get() + vec2(0.0, -size * 3.0),
+                    player.interpolation.get() + vec2(0.0, -size - name_size),
                     geng::TextAlign::CENTER,
-                    size * 2.0,
+                    name_size,
                     player.color,
-                    size * 0.1,
-                    Rgba::BLACK,
+                    name_size * 0.05,
+                    player.team.map_or(Rgba::BLACK, team_color),
                 );
             }
+            if let Some((text, time)) = &player.bubble {
+                if self.time - time < CHAT_BUBBLE_TIME {
+                    self.geng.default_font().draw_with_outline(
+                        framebuffer,
+                        &self.camera,
+                        text,
+                        player.interpolation.get() + vec2(0.0, size * 2.0),
+                        geng::TextAlign::CENTER,
+                        name_size,
+                        Rgba::WHITE,
+                        name_size * 0.05,
+                        Rgba::BLACK,
+                    );
+                }
+            }
+        }
+
+        self.draw_pings(framebuffer);
+        if self.show_minimap {
+            self.draw_minimap(framebuffer);
+        }
+        if self.chat_open {
+            self.draw_chat(framebuffer);
+        }
+        if self.reference_view == ReferenceView::Corner {
+            self.draw_box_lid(framebuffer);
+        }
+        match self.room_config.mode {
+            GameMode::Race => self.draw_leaderboard(framebuffer),
+            GameMode::Teams => self.draw_team_progress(framebuffer),
+            GameMode::Cooperative => {}
+        }
+        if let Some(stats) = self.stats.as_ref().filter(|_| self.show_stats) {
+            self.draw_stats(framebuffer, stats);
         }
     }
     fn handle_event(&mut self, event: geng::Event) {
-        if self.name_typing {
-            // HAHAHAHAHA
-            let player_name = &mut self.players.get_mut(&self.id).unwrap().name;
-            if let geng::Event::KeyDown { key } = event {
-                if key == geng::Key::Backspace {
-                    player_name.pop();
-                }
-                {
-                    let s = format!("{:?}", key);
-                    if s.len() == 1 && player_name.len() < 15 {
-                        player_name.push_str(&s);
-                    }
-                }
+        // Text itself is edited by the focused text input
+        if let geng::Event::KeyDown {
+            key: geng::Key::Enter,
+        } = event
+        {
+            if self.chat_typing {
+                self.send_chat();
             }
         }
         match event {
@@ -687,8 +2219,9 @@ impl geng::State for Game {
                 let old_world_pos = self
                     .camera
                     .screen_to_world(self.framebuffer_size.map(|x| x as f32), cursor_pos);
-                self.camera.fov =
-                    (self.camera.fov - delta as f32 * SENSITIVITY).clamp(FOV_MIN, FOV_MAX);
+                self.camera.fov = self.clamp_fov(
+                    self.camera.fov - delta as f32 * SENSITIVITY * self.settings.zoom_sensitivity,
+                );
                 let new_world_pos = self
                     .camera
                     .screen_to_world(self.framebuffer_size.map(|x| x as f32), cursor_pos);
@@ -702,17 +2235,45 @@ impl geng::State for Game {
                     self.click(position);
                 }
                 geng::MouseButton::Right => {
-                    self.start_drag(Dragging {
-                        initial_screen_pos: position,
-                        target: DragTarget::Camera {
-                            initial_camera_pos: self.camera.center,
-                        },
-                    });
+                    if let Some(step) = self
+                        .room_config
+                        .rotation
+                        .step()
+                        .filter(|_| self.holding_tile())
+                    {
+                        self.rotate_grabbed(-step);
+                    } else {
+                        self.start_drag(Dragging {
+                            initial_screen_pos: position,
+                            target: DragTarget::Camera {
+                                initial_camera_pos: self.camera.center,
+                            },
+                        });
+                    }
+                }
+                geng::MouseButton::Middle => {
+                    self.update_cursor(position);
+                    self.ping();
                 }
-                geng::MouseButton::Middle => {}
             },
-            geng::Event::MouseUp { .. } => {
-                self.release();
+            geng::Event::MouseUp { button, .. } => {
+                // Right click while holding a tile only rotates it, if rotation is enabled
+                if button != geng::MouseButton::Right
+                    || !self.holding_tile()
+                    || self.room_config.rotation.step().is_none()
+                {
+                    self.release();
+                }
+            }
+            geng::Event::KeyDown { key } if self.rebinding.is_some() => {
+                let action = self.rebinding.take().unwrap();
+                self.keymap.bind(action, key);
+                self.keymap.save();
+            }
+            geng::Event::KeyDown { key } if !self.typing() => {
+                if let Some(action) = self.keymap.action(key) {
+                    self.handle_action(action);
+                }
             }
             geng::Event::TouchStart { touches } => self.touch(touches),
             geng::Event::TouchMove { touches } => self.update_touches(touches),
@@ -728,28 +2289,28 @@ pub fn run(geng: &Geng, addr: &str, room: &str, name: Option<String>) -> impl ge
     let future = {
         let geng = geng.clone();
         let room = room.to_owned();
-        let connection = geng::net::client::connect(addr);
+        let addr = addr.to_owned();
+        let connection = geng::net::client::connect(&addr);
         async move {
             let assets: Rc<Assets> = geng::LoadAsset::load(&geng, &run_dir().join("assets"))
                 .await
                 .expect("Failed to load assets");
             let mut connection: game::Connection = connection.await;
-            connection.send(ClientMessage::SelectRoom(room));
+            let name =
+                name.unwrap_or_else(|| batbox::preferences::load("name").unwrap_or_default());
+            connection.send(ClientMessage::SelectRoom {
+                room,
+                name: name.clone(),
+            });
             match connection.next().await {
-                Some(ServerMessage::SetupId {
-                    player_id,
-                    room_config,
-                    tiles,
-                }) => game::Game::new(
-                    &geng,
-                    &assets,
-                    player_id,
-                    name,
-                    room_config,
-                    tiles,
-                    connection,
-                ),
+                Some(ServerMessage::SetupId(setup)) => Box::new(game::Game::new(
+                    &geng, &assets, setup, name, &addr, connection,
+                )) as Box<dyn geng::State>,
                 Some(ServerMessage::RoomNotFound) => panic!("Room not found"),
+                Some(ServerMessage::RoomFull) => {
+                    error!("The race is full");
+                    Box::new(main_menu::run(&geng, &addr))
+                }
                 _ => unreachable!(),
             }
         }
@@ -1,42 +1,123 @@
 use super::*;
 
+use std::f32::consts::PI;
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Id(pub u64);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMessage {
-    SetupId {
-        player_id: Id,
-        room_config: RoomConfig,
-        tiles: Vec<TileState>,
-    },
+    SetupId(RoomSetup),
     RoomNotFound,
+    /// All the boards of the race are taken.
+    RoomFull,
+    InvalidRoomConfig,
     RoomCreated(String),
     PlayerDisconnected(Id),
     UpdatePos(Id, Vec2<f32>),
-    UpdatePlayerName(Id, String),
+    UpdatePlayerProfile(Id, PlayerProfile),
+    TeamAssigned {
+        player: Id,
+        team: usize,
+    },
     TileGrabbed {
         player: Id,
         tile: usize,
         offset: Vec2<f32>,
     },
+    /// Some of the requested tiles were already grabbed, so none of the listed ones were.
+    GrabDenied(Vec<usize>),
     TileReleased {
         player: Id,
         tile: usize,
         pos: Vec2<f32>,
     },
+    TileRotated {
+        player: Id,
+        tile: usize,
+        angle: f32,
+    },
+    TileFlipped {
+        tile: usize,
+        flipped: bool,
+    },
     ConnectTiles(usize, usize),
+    /// A tray was created or changed.
+    TrayUpdated(Tray),
+    TrayRemoved(u64),
+    Chat(ChatMessage),
+    Ping {
+        player: Id,
+        pos: Vec2<f32>,
+    },
+    /// Sent on the first move of the room and whenever two groups are joined.
+    Progress(Progress),
+    PuzzleFinished(PuzzleStats),
+    /// Progress of every racer in the room.
+    Leaderboard(Vec<RaceEntry>),
+    /// Everyone in the room should join the new puzzle.
+    MoveToRoom(String),
+}
+
+/// State of the room sent to a player joining it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSetup {
+    pub player_id: Id,
+    pub room_config: RoomConfig,
+    pub tiles: Vec<TileState>,
+    pub trays: Vec<Tray>,
+    /// Color picked by the server to differ from other players.
+    pub default_color: Rgba<f32>,
+    /// Recent chat messages of the room.
+    pub chat: Vec<ChatMessage>,
+    /// Results if the puzzle is already finished.
+    pub stats: Option<PuzzleStats>,
+    pub progress: Progress,
+    /// Team of the player, in team mode.
+    pub team: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
-    UpdateName(String),
+    UpdateProfile(PlayerProfile),
     CreateRoom(RoomConfig),
-    SelectRoom(String),
+    /// The name lets a racer who reconnects continue on their board.
+    SelectRoom {
+        room: String,
+        name: String,
+    },
     UpdatePos(Vec2<f32>),
-    GrabTile { tile: usize, offset: Vec2<f32> },
+    /// Grab several tile groups at once, with offsets from the cursor.
+    GrabTiles(Vec<(usize, Vec2<f32>)>),
     ReleaseTile(Vec<(usize, Vec2<f32>)>),
+    RotateTile {
+        tile: usize,
+        angle: f32,
+    },
+    FlipTile(usize),
+    /// Revert the last own move, or the last move of the given tile.
+    /// Only the room owner can undo moves of other players.
+    Undo(Option<usize>),
+    /// Move all loose face up edge pieces next to the puzzle.
+    GatherEdges,
+    /// Lay out all loose groups around the puzzle so they don't overlap.
+    SpreadPieces,
     ConnectTiles(usize, usize),
+    CreateTray {
+        name: String,
+        area: AABB<f32>,
+        private: bool,
+    },
+    ResizeTray {
+        id: u64,
+        area: AABB<f32>,
+    },
+    RemoveTray(u64),
+    Chat(String),
+    /// Point at a place on the table for everyone in the room.
+    Ping(Vec2<f32>),
+    /// Start a new puzzle with the same config for everyone in the finished room.
+    NewPuzzle,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,11 +125,231 @@ pub struct RoomConfig {
     pub seed: u64,
     pub size: Vec2<usize>,
     pub image: usize,
+    /// Configs saved before versioning was introduced use the original generator.
+    #[serde(default)]
+    pub generator_version: u32,
+    #[serde(default)]
+    pub rotation: RotationMode,
+    /// Pieces may start face down and need to be flipped over.
+    #[serde(default)]
+    pub double_sided: bool,
+    /// Players can not look at the full picture.
+    #[serde(default)]
+    pub hide_reference: bool,
+    #[serde(default)]
+    pub mode: GameMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum GameMode {
+    /// Everyone assembles the same pieces together
+    #[default]
+    Cooperative,
+    /// Every player assembles their own copy of the pieces
+    Race,
+    /// Two teams assemble their own copies on the halves of one table
+    Teams,
+}
+
+impl GameMode {
+    /// Number of copies of the pieces on a table.
+    pub fn copies(self) -> usize {
+        match self {
+            Self::Teams => TEAMS.len(),
+            _ => 1,
+        }
+    }
+}
+
+pub const TEAMS: [(&str, [f32; 3]); 2] = [("red", [0.9, 0.3, 0.3]), ("blue", [0.3, 0.5, 1.0])];
+
+pub fn team_color(team: usize) -> Rgba<f32> {
+    let [r, g, b] = TEAMS[team % TEAMS.len()].1;
+    Rgba::new(r, g, b, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum RotationMode {
+    /// Pieces are always upright
+    #[default]
+    None,
+    /// Pieces are rotated by multiples of 90 degrees
+    Quarter,
+    /// Pieces can have any rotation
+    Free,
+}
+
+impl RotationMode {
+    /// Angle of a single rotation action, if rotation is enabled.
+    pub fn step(self) -> Option<f32> {
+        match self {
+            Self::None => None,
+            Self::Quarter => Some(PI / 2.0),
+            Self::Free => Some(PI / 12.0),
+        }
+    }
+
+    /// Snaps the angle to the allowed orientations.
+    pub fn round(self, angle: f32) -> f32 {
+        match self {
+            Self::Quarter => normalize_angle((angle / (PI / 2.0)).round() * PI / 2.0),
+            _ => angle,
+        }
+    }
+
+    pub fn random_angle(self, rng: &mut impl Rng) -> f32 {
+        match self {
+            Self::None => 0.0,
+            Self::Quarter => rng.gen_range(0..4) as f32 * PI / 2.0,
+            Self::Free => rng.gen_range(0.0..2.0 * PI),
+        }
+    }
+}
+
+/// Angle in the `[0, 2 * PI)` range.
+pub fn normalize_angle(angle: f32) -> f32 {
+    angle.rem_euclid(2.0 * PI)
+}
+
+/// Smallest absolute difference between two angles.
+pub fn angle_distance(a: f32, b: f32) -> f32 {
+    let d = normalize_angle(a - b);
+    d.min(2.0 * PI - d)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TileState {
     pub grabbed_by: Option<Id>,
     pub pos: Vec2<f32>,
+    pub angle: f32,
+    pub flipped: bool,
     pub connections: Vec<usize>,
 }
+
+/// Named region of the table for sorting pieces.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tray {
+    pub id: u64,
+    pub name: String,
+    pub area: AABB<f32>,
+    /// Private trays can only be changed by their owner,
+    /// and other players can not take pieces from them.
+    pub owner: Option<Id>,
+}
+
+impl Tray {
+    /// Smallest allowed size of a tray side.
+    pub const MIN_SIZE: f32 = 0.5;
+
+    pub fn can_edit(&self, player: Id) -> bool {
+        self.owner.is_none_or(|owner| owner == player)
+    }
+
+    /// Whether the piece at the position is protected from the player.
+    pub fn protects(&self, player: Id, pos: Vec2<f32>) -> bool {
+        !self.can_edit(player) && self.area.contains(pos)
+    }
+}
+
+/// Colors offered to players, the server hands them out in order.
+pub const PLAYER_COLORS: [(&str, [f32; 3]); 8] = [
+    ("red", [0.9, 0.2, 0.2]),
+    ("orange", [1.0, 0.6, 0.1]),
+    ("yellow", [0.95, 0.9, 0.2]),
+    ("green", [0.3, 0.8, 0.3]),
+    ("cyan", [0.2, 0.8, 0.9]),
+    ("blue", [0.3, 0.4, 1.0]),
+    ("purple", [0.7, 0.3, 0.9]),
+    ("pink", [1.0, 0.5, 0.8]),
+];
+
+pub fn player_color(index: usize) -> Rgba<f32> {
+    let [r, g, b] = PLAYER_COLORS[index % PLAYER_COLORS.len()].1;
+    Rgba::new(r, g, b, 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum CursorStyle {
+    #[default]
+    Hand,
+    Dot,
+}
+
+/// How a player is shown to others.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerProfile {
+    pub name: String,
+    pub color: Rgba<f32>,
+    #[serde(default)]
+    pub cursor: CursorStyle,
+}
+
+/// Longest player name in grapheme clusters.
+pub const NAME_MAX: usize = 15;
+
+/// Longest tray name in grapheme clusters.
+pub const TRAY_NAME_MAX: usize = 30;
+
+/// Longest chat message in grapheme clusters.
+pub const CHAT_MESSAGE_MAX: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub player: Id,
+    /// Name of the player when the message was sent.
+    pub name: String,
+    pub text: String,
+    /// Unix time in seconds.
+    pub timestamp: u64,
+}
+
+/// How much a player helped to finish the puzzle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub player: Id,
+    /// Name of the player at their last connection.
+    pub name: String,
+    pub color: Rgba<f32>,
+    /// Number of groups joined by the player.
+    pub connected: usize,
+    /// Most connections made before another player made one.
+    pub longest_streak: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PuzzleStats {
+    /// Seconds from the first move to the last connection.
+    pub time: f64,
+    pub players: Vec<PlayerStats>,
+    /// The team that finished its copy first, in team mode.
+    pub winning_team: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progress {
+    pub pieces: usize,
+    /// Groups of connected pieces, one per piece at the start.
+    pub groups: usize,
+    /// Seconds since the first piece was grabbed.
+    pub since_first_move: Option<f64>,
+    /// Seconds from the first move to the last connection, once finished.
+    pub finish_time: Option<f64>,
+}
+
+impl Progress {
+    /// How much of the puzzle is assembled, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        if self.pieces <= 1 {
+            return 1.0;
+        }
+        (self.pieces - self.groups) as f32 / (self.pieces - 1) as f32
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaceEntry {
+    pub player: Id,
+    pub name: String,
+    pub color: Rgba<f32>,
+    pub progress: Progress,
+}
@@ -1,38 +1,86 @@
 use super::*;
 
-type Mesh = Vec<[JigsawVertex; 3]>;
+pub type Mesh = Vec<[JigsawVertex; 3]>;
 
-pub fn generate_jigsaw(
-    ugli: &Ugli,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerationError {
+    /// The puzzle has zero pieces along one of the axes.
+    EmptyPuzzle { pieces: Vec2<usize> },
+    /// `earcutr` failed to triangulate the outline of a piece.
+    Triangulation { tile: usize },
+    /// The generator version is newer than this build knows about.
+    UnknownVersion(u32),
+}
+
+impl std::fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyPuzzle { pieces } => {
+                write!(f, "puzzle of size {}x{} has no pieces", pieces.x, pieces.y)
+            }
+            Self::Triangulation { tile } => write!(f, "failed to triangulate tile #{tile}"),
+            Self::UnknownVersion(version) => write!(f, "unknown generator version {version}"),
+        }
+    }
+}
+
+impl std::error::Error for GenerationError {}
+
+/// Version of the generation algorithm used for new rooms.
+/// Must be bumped on any change that alters the generated geometry,
+/// so that rooms keep the cut they were created with.
+///
+/// - 0: original algorithm
+/// - 1: invalid piece outlines are re-rolled
+pub const GENERATOR_VERSION: u32 = 1;
+
+/// Triangles and outline of every tile, relative to the tile center.
+pub fn generate_meshes(
+    version: u32,
     seed: u64,
     size: Vec2<f32>,
     pieces: Vec2<usize>,
-) -> Vec<(JigsawMesh, ugli::VertexBuffer<JigsawVertex>)> {
-    let outlines = outline_vertices(size, pieces, jigsaw(seed, size, pieces));
-    let triangles = triangulate(&outlines);
-    finalize_meshes(ugli, triangles, outlines)
-}
-
-fn finalize_meshes(
-    ugli: &Ugli,
-    triangles: Vec<Mesh>,
-    outlines: Vec<Vec<JigsawVertex>>,
-) -> Vec<(JigsawMesh, ugli::VertexBuffer<JigsawVertex>)> {
-    triangles
-        .into_iter()
-        .zip(outlines)
-        .map(|(mesh, outline)| {
-            (
-                ugli::VertexBuffer::new_dynamic(ugli, mesh.into_iter().flatten().collect()),
-                ugli::VertexBuffer::new_dynamic(ugli, outline),
-            )
-        })
-        .collect()
+) -> Result<Vec<(Mesh, Vec<JigsawVertex>)>, GenerationError> {
+    let outlines = outline_vertices(size, pieces, jigsaw(version, seed, size, pieces)?);
+    let triangles = triangulate(&outlines)?;
+    Ok(triangles.into_iter().zip(outlines).collect())
 }
 
 type Polygon = Vec<Vec2<f32>>;
 
-fn jigsaw(seed: u64, size: Vec2<f32>, pieces: Vec2<usize>) -> Vec<Polygon> {
+fn jigsaw(
+    version: u32,
+    seed: u64,
+    size: Vec2<f32>,
+    pieces: Vec2<usize>,
+) -> Result<Vec<Polygon>, GenerationError> {
+    Ok(layout(version, seed, size, pieces)?.polygons())
+}
+
+/// Cut lines of the puzzle: the outer border followed by every edge between two pieces.
+/// Unlike the piece outlines, each shared edge is only present once.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn cut_lines(
+    version: u32,
+    seed: u64,
+    size: Vec2<f32>,
+    pieces: Vec2<usize>,
+) -> Result<Vec<Vec<Vec2<f32>>>, GenerationError> {
+    Ok(layout(version, seed, size, pieces)?.cut_lines())
+}
+
+fn layout(
+    version: u32,
+    seed: u64,
+    size: Vec2<f32>,
+    pieces: Vec2<usize>,
+) -> Result<Layout, GenerationError> {
+    if version > GENERATOR_VERSION {
+        return Err(GenerationError::UnknownVersion(version));
+    }
+    if pieces.x == 0 || pieces.y == 0 {
+        return Err(GenerationError::EmptyPuzzle { pieces });
+    }
     let mut rng = rand::prelude::StdRng::seed_from_u64(seed);
     let tile_size = size / pieces.map(|x| x as f32);
     let mut vertices: Vec<Vec2<f32>> = (0..=pieces.y)
@@ -51,16 +99,16 @@ fn jigsaw(seed: u64, size: Vec2<f32>, pieces: Vec2<usize>) -> Vec<Polygon> {
         }
         *v += vec2(rng.gen_range(-dx..=dx), rng.gen_range(-dy..=dy));
     }
-    let mut jigsaw: Vec<[Vec<Vec2<f32>>; 4]> = (0..pieces.y)
+    let corners: Vec<[Vec2<f32>; 4]> = (0..pieces.y)
         .flat_map(|y| {
             let vertices = &vertices;
             (0..pieces.x).map(move |x| {
                 let i = x + y * (pieces.x + 1);
                 [
-                    vec![vertices[i]],
-                    vec![vertices[i + 1]],
-                    vec![vertices[i + 1 + pieces.x + 1]],
-                    vec![vertices[i + pieces.x + 1]],
+                    vertices[i],
+                    vertices[i + 1],
+                    vertices[i + 1 + pieces.x + 1],
+                    vertices[i + pieces.x + 1],
                 ]
             })
         })
@@ -81,22 +129,27 @@ fn jigsaw(seed: u64, size: Vec2<f32>, pieces: Vec2<usize>) -> Vec<Polygon> {
             })
             .collect()
     };
-    let mut edges: Vec<Vec<Vec2<f32>>> = (0..edges_count)
+    let mut shapes: Vec<Vec<Vec2<f32>>> = (0..edges_count)
         .map(|i| {
-            let t = i as f32 / (edges_count as f32 - 1.0);
-            let start = 0.3 + t * 0.2;
-            let end = start + 0.2;
-            itertools::chain![
-                [vec2(start, 0.0)],
-                knob.iter().map(|v| *v + vec2(start, 0.0)),
-                [vec2(end, 0.0)],
-            ]
-            .collect()
+            // Single edge puzzles (1x2 and 2x1) would divide by zero
+            let t = if edges_count > 1 {
+                i as f32 / (edges_count as f32 - 1.0)
+            } else {
+                0.5
+            };
+            edge_shape(&knob, 0.3 + t * 0.2, 1.0)
         })
         .collect();
-    edges.shuffle(&mut rng);
+    shapes.shuffle(&mut rng);
 
-    for (i, mut edge) in edges.into_iter().enumerate() {
+    let mut layout = Layout {
+        pieces,
+        tile_size,
+        corners,
+        sides: vec![[None; 4]; pieces.x * pieces.y],
+        edges: Vec::with_capacity(edges_count),
+    };
+    for (i, mut shape) in shapes.into_iter().enumerate() {
         let vertical = i < vertical_edges;
         let tile = if vertical {
             i / pieces.y + i % pieces.y * pieces.x
@@ -106,32 +159,209 @@ fn jigsaw(seed: u64, size: Vec2<f32>, pieces: Vec2<usize>) -> Vec<Polygon> {
 
         if rng.gen() {
             // Flip edge
-            edge.iter_mut().for_each(|v| *v = vec2(v.x, -v.y));
+            shape.iter_mut().for_each(|v| *v = vec2(v.x, -v.y));
         }
 
-        let pos = vec2(tile % pieces.x, tile / pieces.x).map(|x| x as f32) * tile_size;
         let other = if vertical { tile + 1 } else { tile + pieces.x };
-        let scale = if vertical { tile_size.y } else { tile_size.x };
         if vertical {
-            edge.iter_mut()
+            layout.sides[tile][1] = Some((i, false));
+            layout.sides[other][3] = Some((i, true));
+        } else {
+            layout.sides[tile][2] = Some((i, true));
+            layout.sides[other][0] = Some((i, false));
+        }
+        let mut edge = Edge {
+            tile,
+            other,
+            vertical,
+            rerolls: 0,
+            points: vec![],
+        };
+        edge.points = layout.place(&edge, shape);
+        layout.edges.push(edge);
+    }
+
+    if version >= 1 {
+        layout.fix_invalid_edges(seed, &knob);
+    }
+    Ok(layout)
+}
+
+/// Edge shape in local coordinates, with the edge going from `(0, 0)` to `(1, 0)`.
+fn edge_shape(knob: &[Vec2<f32>], start: f32, knob_scale: f32) -> Vec<Vec2<f32>> {
+    itertools::chain![
+        [vec2(start, 0.0)],
+        knob.iter().map(|v| *v * knob_scale + vec2(start, 0.0)),
+        [vec2(start + 0.2 * knob_scale, 0.0)],
+    ]
+    .collect()
+}
+
+/// How many times an edge is re-rolled before giving up and making it straight.
+const MAX_REROLLS: u32 = 8;
+/// Knob size multiplier applied on every re-roll, so the knobs eventually fit.
+const REROLL_KNOB_SHRINK: f32 = 0.85;
+/// Minimal allowed distance between different sides of a piece relative to the tile size.
+const KNOB_CLEARANCE: f32 = 0.02;
+
+struct Edge {
+    tile: usize,
+    other: usize,
+    vertical: bool,
+    rerolls: u32,
+    points: Vec<Vec2<f32>>,
+}
+
+struct Layout {
+    pieces: Vec2<usize>,
+    tile_size: Vec2<f32>,
+    corners: Vec<[Vec2<f32>; 4]>,
+    /// For every side of every tile: the edge on that side and whether it is reversed.
+    sides: Vec<[Option<(usize, bool)>; 4]>,
+    edges: Vec<Edge>,
+}
+
+impl Layout {
+    fn place(&self, edge: &Edge, mut shape: Vec<Vec2<f32>>) -> Vec<Vec2<f32>> {
+        let tile_size = self.tile_size;
+        let tile = edge.tile;
+        let pos = vec2(tile % self.pieces.x, tile / self.pieces.x).map(|x| x as f32) * tile_size;
+        let scale = if edge.vertical {
+            tile_size.y
+        } else {
+            tile_size.x
+        };
+        if edge.vertical {
+            shape
+                .iter_mut()
                 .for_each(|v| *v = v.rotate_90() * scale + pos + vec2(tile_size.x, 0.0));
         } else {
-            edge.iter_mut()
+            shape
+                .iter_mut()
                 .for_each(|v| *v = *v * scale + pos + vec2(0.0, tile_size.y));
         }
-        if vertical {
-            jigsaw[other][3].extend(edge.iter().rev().copied());
-            jigsaw[tile][1].extend(edge);
-        } else {
-            jigsaw[tile][2].extend(edge.iter().rev().copied());
-            jigsaw[other][0].extend(edge);
+        shape
+    }
+
+    /// Polygon of the tile together with the edge that each vertex (and the segment starting
+    /// at it) belongs to. Border sides have no edge.
+    fn polygon(&self, tile: usize) -> (Polygon, Vec<Option<usize>>) {
+        let mut polygon = Vec::new();
+        let mut owners = Vec::new();
+        for (corner, side) in self.corners[tile].iter().zip(self.sides[tile]) {
+            let edge = side.map(|(edge, _)| edge);
+            polygon.push(*corner);
+            owners.push(edge);
+            if let Some((edge, reversed)) = side {
+                let points = &self.edges[edge].points;
+                if reversed {
+                    polygon.extend(points.iter().rev().copied());
+                } else {
+                    polygon.extend(points.iter().copied());
+                }
+                owners.extend(std::iter::repeat(Some(edge)).take(points.len()));
+            }
         }
+        (polygon, owners)
     }
 
-    jigsaw
-        .into_iter()
-        .map(|sides| sides.into_iter().flatten().collect())
-        .collect()
+    /// Edges of the tile that make it self-intersecting or come too close to another side.
+    fn invalid_edges(&self, tile: usize) -> BTreeSet<usize> {
+        let clearance = KNOB_CLEARANCE * partial_min(self.tile_size.x, self.tile_size.y);
+        let (polygon, owners) = self.polygon(tile);
+        let n = polygon.len();
+        let segment = |i: usize| (polygon[i], polygon[(i + 1) % n]);
+        let mut invalid = BTreeSet::new();
+        for i in 0..n {
+            // Skip the neighbouring segments since they share a vertex
+            for j in i + 2..n {
+                if i == 0 && j == n - 1 {
+                    continue;
+                }
+                let (a, b) = segment(i);
+                let (c, d) = segment(j);
+                let bad = if owners[i] == owners[j] {
+                    util::segments_intersect(a, b, c, d)
+                } else {
+                    util::segment_distance(a, b, c, d) < clearance
+                };
+                if bad {
+                    invalid.extend(owners[i]);
+                    invalid.extend(owners[j]);
+                }
+            }
+        }
+        invalid
+    }
+
+    /// Deterministically re-rolls invalid edges until every piece is a simple polygon.
+    fn fix_invalid_edges(&mut self, seed: u64, knob: &[Vec2<f32>]) {
+        let mut dirty: BTreeSet<usize> = (0..self.corners.len()).collect();
+        while !dirty.is_empty() {
+            let invalid: BTreeSet<usize> = dirty
+                .iter()
+                .flat_map(|&tile| self.invalid_edges(tile))
+                .collect();
+            dirty.clear();
+            for edge in invalid {
+                let rerolls = self.edges[edge].rerolls;
+                let shape = if rerolls < MAX_REROLLS {
+                    let mut rng = reroll_rng(seed, edge, rerolls);
+                    let knob_scale = REROLL_KNOB_SHRINK.powi(rerolls as i32 + 1);
+                    let start = rng.gen_range(0.3..=0.7 - 0.2 * knob_scale);
+                    let mut shape = edge_shape(knob, start, knob_scale);
+                    if rng.gen() {
+                        shape.iter_mut().for_each(|v| *v = vec2(v.x, -v.y));
+                    }
+                    shape
+                } else if !self.edges[edge].points.is_empty() {
+                    // Straight edge
+                    vec![]
+                } else {
+                    continue;
+                };
+                let points = self.place(&self.edges[edge], shape);
+                let edge = &mut self.edges[edge];
+                edge.rerolls += 1;
+                edge.points = points;
+                dirty.insert(edge.tile);
+                dirty.insert(edge.other);
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cut_lines(&self) -> Vec<Vec<Vec2<f32>>> {
+        let last_row = self.pieces.x * (self.pieces.y - 1);
+        let border = vec![
+            self.corners[0][0],
+            self.corners[self.pieces.x - 1][1],
+            self.corners[self.corners.len() - 1][2],
+            self.corners[last_row][3],
+            self.corners[0][0],
+        ];
+        let edges = self.edges.iter().map(|edge| {
+            let corners = &self.corners[edge.tile];
+            let (start, end) = if edge.vertical {
+                (corners[1], corners[2])
+            } else {
+                (corners[3], corners[2])
+            };
+            itertools::chain![[start], edge.points.iter().copied(), [end]].collect()
+        });
+        itertools::chain![[border], edges].collect()
+    }
+
+    fn polygons(&self) -> Vec<Polygon> {
+        (0..self.corners.len())
+            .map(|tile| self.polygon(tile).0)
+            .collect()
+    }
+}
+
+fn reroll_rng(seed: u64, edge: usize, reroll: u32) -> rand::prelude::StdRng {
+    let salt = ((edge as u64) << 8 | reroll as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    rand::prelude::StdRng::seed_from_u64(seed ^ salt)
 }
 
 fn outline_vertices(
@@ -157,23 +387,161 @@ fn outline_vertices(
         .collect()
 }
 
-fn triangulate(polygons: &[Vec<JigsawVertex>]) -> Vec<Mesh> {
+fn triangulate(polygons: &[Vec<JigsawVertex>]) -> Result<Vec<Mesh>, GenerationError> {
     polygons
         .iter()
-        .map(|polygon| {
+        .enumerate()
+        .map(|(tile, polygon)| {
             let flat_polygon: Vec<f32> = polygon
                 .iter()
                 .flat_map(|v| [v.a_pos.x, v.a_pos.y])
                 .collect();
-            let triangles =
-                earcutr::earcut(&flat_polygon, &[], 2).expect("Failed to triangulate mesh");
-            triangles
+            let triangles = earcutr::earcut(&flat_polygon, &[], 2)
+                .map_err(|_| GenerationError::Triangulation { tile })?;
+            Ok(triangles
                 .chunks(3)
                 .map(|triangle| {
                     let triangle = [triangle[0], triangle[1], triangle[2]];
                     triangle.map(|i| polygon[i])
                 })
-                .collect()
+                .collect())
         })
         .collect()
 }
+
+#[test]
+fn test_generation_sizes() {
+    let size = vec2(4.0, 3.0);
+    for x in 1..=8 {
+        for y in 1..=8 {
+            let pieces = vec2(x, y);
+            for seed in 0..20 {
+                let polygons = jigsaw(GENERATOR_VERSION, seed, size, pieces).unwrap();
+                assert_eq!(polygons.len(), x * y);
+                let meshes = triangulate(&outline_vertices(size, pieces, polygons)).unwrap();
+                // Pieces must cover the whole image without gaps or overlaps
+                let area: f32 = meshes
+                    .iter()
+                    .flatten()
+                    .map(|[a, b, c]| util::line_signed_d(a.a_pos, b.a_pos, c.a_pos).abs() / 2.0)
+                    .sum();
+                assert!(
+                    (area - size.x * size.y).abs() < 1e-3,
+                    "area of {x}x{y} (seed {seed}) is {area}",
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_generation_empty() {
+    for pieces in [vec2(0, 0), vec2(0, 5), vec2(5, 0)] {
+        assert_eq!(
+            jigsaw(GENERATOR_VERSION, 0, vec2(1.0, 1.0), pieces),
+            Err(GenerationError::EmptyPuzzle { pieces }),
+        );
+    }
+}
+
+#[test]
+fn test_generation_valid() {
+    for (size, pieces) in [
+        (vec2(4.0, 3.0), vec2(6, 4)),
+        (vec2(5.0, 1.0), vec2(5, 5)),
+        (vec2(1.0, 5.0), vec2(5, 5)),
+    ] {
+        for seed in 0..2000 {
+            let layout = layout(GENERATOR_VERSION, seed, size, pieces).unwrap();
+            for tile in 0..pieces.x * pieces.y {
+                assert!(
+                    layout.invalid_edges(tile).is_empty(),
+                    "tile #{tile} of {}x{} (seed {seed}) is invalid",
+                    pieces.x,
+                    pieces.y,
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_generation_deterministic() {
+    let size = vec2(5.0, 1.0);
+    let pieces = vec2(5, 5);
+    for seed in 0..100 {
+        assert_eq!(
+            jigsaw(GENERATOR_VERSION, seed, size, pieces).unwrap(),
+            jigsaw(GENERATOR_VERSION, seed, size, pieces).unwrap(),
+        );
+    }
+}
+
+/// Hashes of generated pieces for every generator version.
+/// Tiles are only ever allowed to change together with [GENERATOR_VERSION].
+/// Missing entries fail the test, `UPDATE_GOLDEN=1` rewrites the file.
+#[test]
+fn test_generation_golden() {
+    const PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/jigsaw/gen.golden");
+    fn hash(polygons: &[Polygon]) -> u64 {
+        // FNV-1a, since std hashers are not guaranteed to be stable
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for v in polygons
+            .iter()
+            .flat_map(|polygon| polygon.iter().chain([&Vec2::ZERO]))
+        {
+            for byte in [v.x, v.y]
+                .into_iter()
+                .flat_map(|x| x.to_bits().to_le_bytes())
+            {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    let update = std::env::var("UPDATE_GOLDEN").is_ok();
+    let mut golden: BTreeMap<String, String> = if update {
+        BTreeMap::new()
+    } else {
+        std::fs::read_to_string(PATH)
+            .expect("golden file is missing, run with UPDATE_GOLDEN=1")
+            .lines()
+            .filter_map(|line| line.rsplit_once(' '))
+            .map(|(key, hash)| (key.to_owned(), hash.to_owned()))
+            .collect()
+    };
+    let mut missing = Vec::new();
+    for version in 0..=GENERATOR_VERSION {
+        for seed in [0, 1, 42, 123, 1155] {
+            for (x, y) in [(1, 1), (1, 5), (5, 1), (4, 3), (10, 10), (40, 25)] {
+                let polygons = jigsaw(version, seed, vec2(8.0, 5.0), vec2(x, y)).unwrap();
+                let key = format!("v{version} seed={seed} {x}x{y}");
+                let hash = format!("{:016x}", hash(&polygons));
+                if update {
+                    golden.insert(key, hash);
+                    continue;
+                }
+                match golden.get(&key) {
+                    Some(expected) => assert_eq!(
+                        *expected, hash,
+                        "generated geometry changed for {key}, bump GENERATOR_VERSION",
+                    ),
+                    None => missing.push(key),
+                }
+            }
+        }
+    }
+    if update {
+        let contents: String = golden
+            .into_iter()
+            .map(|(key, hash)| format!("{key} {hash}\n"))
+            .collect();
+        std::fs::write(PATH, contents).unwrap();
+    }
+    assert!(
+        missing.is_empty(),
+        "no golden hashes for {missing:?}, run with UPDATE_GOLDEN=1",
+    );
+}
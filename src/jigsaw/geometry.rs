@@ -0,0 +1,128 @@
+use super::*;
+
+/// Shape of a single tile, not tied to the GPU.
+#[derive(Debug, Clone)]
+pub struct TileGeometry {
+    pub puzzle_pos: Vec2<usize>,
+    /// Outline of the tile relative to its center.
+    pub outline: Vec<JigsawVertex>,
+    pub triangles: gen::Mesh,
+    /// Tiles sharing an edge with this one.
+    pub neighbours: Vec<usize>,
+}
+
+/// Geometry of the whole puzzle, usable both by the client and the server.
+#[derive(Debug, Clone)]
+pub struct PuzzleGeometry {
+    pub size: Vec2<f32>,
+    pub pieces: Vec2<usize>,
+    pub tile_size: Vec2<f32>,
+    pub tiles: Vec<TileGeometry>,
+}
+
+impl PuzzleGeometry {
+    pub fn generate(
+        version: u32,
+        seed: u64,
+        size: Vec2<f32>,
+        pieces: Vec2<usize>,
+    ) -> Result<Self, GenerationError> {
+        let tiles = gen::generate_meshes(version, seed, size, pieces)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, (triangles, outline))| {
+                let puzzle_pos = vec2(i % pieces.x, i / pieces.x);
+                let mut neighbours = Vec::new();
+                if puzzle_pos.x > 0 {
+                    neighbours.push(i - 1);
+                }
+                if puzzle_pos.x + 1 < pieces.x {
+                    neighbours.push(i + 1);
+                }
+                if puzzle_pos.y > 0 {
+                    neighbours.push(i - pieces.x);
+                }
+                if puzzle_pos.y + 1 < pieces.y {
+                    neighbours.push(i + pieces.x);
+                }
+                TileGeometry {
+                    puzzle_pos,
+                    outline,
+                    triangles,
+                    neighbours,
+                }
+            })
+            .collect();
+        Ok(Self {
+            size,
+            pieces,
+            tile_size: size / pieces.map(|x| x as f32),
+            tiles,
+        })
+    }
+
+    pub fn from_room_config(
+        config: &RoomConfig,
+        image_size: Vec2<usize>,
+    ) -> Result<Self, GenerationError> {
+        Self::generate(
+            config.generator_version,
+            config.seed,
+            puzzle_size(image_size),
+            config.size,
+        )
+    }
+
+    /// Bounding box of the tile placed at the position with the given rotation.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn tile_bounds(&self, tile: usize, pos: Vec2<f32>, angle: f32) -> AABB<f32> {
+        util::bounding_box(
+            self.tiles[tile]
+                .outline
+                .iter()
+                .map(|v| pos + v.a_pos.rotate(angle)),
+        )
+        .unwrap_or(AABB::point(pos))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn are_adjacent(&self, a: usize, b: usize) -> bool {
+        self.tiles
+            .get(a)
+            .is_some_and(|tile| tile.neighbours.contains(&b))
+    }
+}
+
+impl TileGeometry {
+    /// Whether the point (relative to the tile center) is inside the tile.
+    pub fn contains(&self, pos: Vec2<f32>) -> bool {
+        self.triangles
+            .iter()
+            .any(|triangle| util::triangle_contains(triangle.map(|v| v.a_pos), pos))
+    }
+
+    /// Whether the tile lies on the border of the puzzle.
+    pub fn is_edge(&self) -> bool {
+        self.neighbours.len() < 4
+    }
+}
+
+#[test]
+fn test_geometry_headless() {
+    let geometry =
+        PuzzleGeometry::generate(GENERATOR_VERSION, 0, vec2(4.0, 3.0), vec2(4, 3)).unwrap();
+    assert_eq!(geometry.tiles.len(), 12);
+    for (i, tile) in geometry.tiles.iter().enumerate() {
+        assert!(tile.contains(Vec2::ZERO));
+        for &other in &tile.neighbours {
+            assert!(geometry.are_adjacent(other, i));
+            let delta = tile.puzzle_pos.map(|x| x as i32)
+                - geometry.tiles[other].puzzle_pos.map(|x| x as i32);
+            assert_eq!(delta.x.abs() + delta.y.abs(), 1);
+        }
+    }
+    assert_eq!(geometry.tiles[0].neighbours.len(), 2);
+    assert_eq!(geometry.tiles[5].neighbours.len(), 4);
+    let edges = geometry.tiles.iter().filter(|tile| tile.is_edge()).count();
+    assert_eq!(edges, 10);
+}
@@ -0,0 +1,109 @@
+use super::*;
+
+/// Gap between spread out groups, relative to the tile size.
+#[cfg(not(target_arch = "wasm32"))]
+pub const SPREAD_GAP: f32 = 0.25;
+
+/// Center of the area where one of several copies of the puzzle is assembled,
+/// side by side with enough room for the spread out pieces of each.
+pub fn copy_origin(puzzle_size: Vec2<f32>, copy: usize, copies: usize) -> Vec2<f32> {
+    let offset = copy as f32 - (copies - 1) as f32 / 2.0;
+    vec2(offset * puzzle_size.x * 2.0, 0.0)
+}
+
+/// Packs boxes into rows alternating below and above the assembled puzzle area,
+/// which is centered at the origin. Returns the bottom left corner for each box.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spread(puzzle_size: Vec2<f32>, gap: f32, boxes: &[AABB<f32>]) -> Vec<Vec2<f32>> {
+    let width = boxes
+        .iter()
+        .map(|aabb| aabb.width())
+        .fold(puzzle_size.x * 1.5, f32::max);
+
+    // Tallest boxes first, so the rows waste less space
+    let mut order: Vec<usize> = (0..boxes.len()).collect();
+    order.sort_by(|&a, &b| boxes[b].height().total_cmp(&boxes[a].height()));
+    let mut rows: Vec<Vec<usize>> = Vec::new();
+    let mut row_width = 0.0;
+    for i in order {
+        let box_width = boxes[i].width();
+        match rows.last_mut() {
+            Some(row) if row_width + box_width <= width => row.push(i),
+            _ => {
+                rows.push(vec![i]);
+                row_width = 0.0;
+            }
+        }
+        row_width += box_width + gap;
+    }
+
+    let mut result = vec![Vec2::ZERO; boxes.len()];
+    let mut below = -puzzle_size.y / 2.0 - gap;
+    let mut above = puzzle_size.y / 2.0 + gap;
+    for (row_index, row) in rows.into_iter().enumerate() {
+        let height = row.iter().map(|&i| boxes[i].height()).fold(0.0, f32::max);
+        let y = if row_index % 2 == 0 {
+            below -= height + gap;
+            below + gap
+        } else {
+            above += height + gap;
+            above - height - gap
+        };
+        let mut x = -width / 2.0;
+        for i in row {
+            result[i] = vec2(x, y);
+            x += boxes[i].width() + gap;
+        }
+    }
+    result
+}
+
+#[test]
+fn test_spread() {
+    let mut rng = rand::prelude::StdRng::seed_from_u64(0);
+    let puzzle_size = vec2(6.0, 4.0);
+    let boxes: Vec<AABB<f32>> = (0..200)
+        .map(|_| {
+            AABB::point(vec2(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+                .extend_positive(vec2(rng.gen_range(0.1..2.0), rng.gen_range(0.1..2.0)))
+        })
+        .collect();
+    let corners = spread(puzzle_size, 0.1, &boxes);
+    let placed: Vec<AABB<f32>> = boxes
+        .iter()
+        .zip(&corners)
+        .map(|(aabb, &corner)| {
+            AABB::point(corner).extend_positive(vec2(aabb.width(), aabb.height()))
+        })
+        .collect();
+    let overlap = |a: &AABB<f32>, b: &AABB<f32>| {
+        a.x_min < b.x_max && b.x_min < a.x_max && a.y_min < b.y_max && b.y_min < a.y_max
+    };
+    let puzzle = AABB::ZERO.extend_symmetric(puzzle_size / 2.0);
+    for (i, a) in placed.iter().enumerate() {
+        assert!(!overlap(a, &puzzle));
+        for b in &placed[i + 1..] {
+            assert!(!overlap(a, b));
+        }
+    }
+}
+
+#[test]
+fn test_spread_nan() {
+    let boxes = [
+        AABB::point(vec2(f32::NAN, 0.0)).extend_positive(vec2(1.0, f32::NAN)),
+        AABB::point(Vec2::ZERO).extend_positive(vec2(1.0, 1.0)),
+    ];
+    assert_eq!(spread(vec2(6.0, 4.0), 0.1, &boxes).len(), 2);
+}
+
+#[test]
+fn test_copy_origin() {
+    let puzzle_size = vec2(6.0, 4.0);
+    assert_eq!(copy_origin(puzzle_size, 0, 1), Vec2::ZERO);
+    let left = copy_origin(puzzle_size, 0, 2);
+    let right = copy_origin(puzzle_size, 1, 2);
+    assert_eq!(left, -right);
+    // The spread out pieces of neighbouring copies must not overlap
+    assert!(right.x - left.x >= puzzle_size.x * 1.5);
+}
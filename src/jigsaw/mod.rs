@@ -1,8 +1,23 @@
 use super::*;
 
 mod gen;
+mod geometry;
+mod layout;
 
-pub type JigsawMesh = ugli::VertexBuffer<JigsawVertex>;
+#[cfg(not(target_arch = "wasm32"))]
+pub use gen::cut_lines;
+pub use gen::{GenerationError, GENERATOR_VERSION};
+pub use geometry::*;
+pub use layout::*;
+
+/// Height of the assembled puzzle in world units.
+pub const PUZZLE_HEIGHT: f32 = 5.0;
+
+/// Size of the assembled puzzle for an image, keeping its aspect ratio.
+pub fn puzzle_size(image_size: Vec2<usize>) -> Vec2<f32> {
+    let size = image_size.map(|x| x as f32);
+    size * PUZZLE_HEIGHT / size.y
+}
 
 #[derive(ugli::Vertex, Debug, Clone, Copy)]
 pub struct JigsawVertex {
@@ -17,35 +32,42 @@ pub struct Jigsaw {
 
 pub struct JigsawTile {
     pub interpolated: Interpolated<Vec2<f32>>,
+    pub angle: f32,
+    /// Whether the tile is face down.
+    pub flipped: bool,
     pub last_interaction_time: f32,
     pub grabbed_by: Option<Id>,
     pub connected_to: Vec<usize>,
+    /// Which copy of the puzzle the tile belongs to, when several are assembled at once.
+    pub copy: usize,
     pub puzzle_pos: Vec2<usize>,
-    pub mesh: JigsawMesh,
+    pub geometry: TileGeometry,
     pub outline: ugli::VertexBuffer<JigsawVertex>,
 }
 
 impl Jigsaw {
-    pub fn generate(ugli: &Ugli, seed: u64, size: Vec2<f32>, pieces: Vec2<usize>) -> Self {
-        let tile_size = size / pieces.map(|x| x as f32);
+    pub fn new(ugli: &Ugli, geometry: PuzzleGeometry, copies: usize) -> Self {
+        let tile_size = geometry.tile_size;
         Self {
             tile_size,
-            tiles: gen::generate_jigsaw(ugli, seed, size, pieces)
-                .into_iter()
-                .enumerate()
-                .map(|(i, (mesh, outline))| {
-                    let puzzle_pos = vec2(i % pieces.x, i / pieces.x);
+            tiles: (0..copies)
+                .flat_map(|copy| geometry.tiles.iter().map(move |geometry| (copy, geometry)))
+                .map(|(copy, geometry)| {
+                    let puzzle_pos = geometry.puzzle_pos;
                     JigsawTile {
                         interpolated: Interpolated::new(
                             puzzle_pos.map(|x| x as f32 + 0.5) * tile_size,
                             Vec2::ZERO,
                         ),
+                        angle: 0.0,
+                        flipped: false,
                         last_interaction_time: 0.0,
                         grabbed_by: None,
                         connected_to: vec![],
+                        copy,
                         puzzle_pos,
-                        mesh,
-                        outline,
+                        outline: ugli::VertexBuffer::new_dynamic(ugli, geometry.outline.clone()),
+                        geometry: geometry.clone(),
                     }
                 })
                 .collect(),
@@ -66,24 +88,38 @@ impl Jigsaw {
         walk_rec(&self.tiles, tile, &mut connected);
         connected
     }
+
+    /// Rotates the tile together with all the tiles connected to it.
+    pub fn set_group_angle(&mut self, tile: usize, angle: f32) {
+        for tile in self.get_all_connected(tile) {
+            self.tiles[tile].angle = angle;
+        }
+    }
+
+    /// Where the tile should be to connect to the other one,
+    /// or `None` if they are not adjacent in the puzzle.
+    pub fn snap_position(&self, tile: usize, other: usize) -> Option<Vec2<f32>> {
+        if self.tiles[tile].copy != self.tiles[other].copy {
+            return None;
+        }
+        let delta = self.tiles[tile].puzzle_pos.map(|x| x as i32)
+            - self.tiles[other].puzzle_pos.map(|x| x as i32);
+        if delta.x.abs() + delta.y.abs() != 1 {
+            return None;
+        }
+        let other = &self.tiles[other];
+        let delta = delta.map(|x| x as f32) * self.tile_size;
+        Some(other.interpolated.get() + delta.rotate(other.angle))
+    }
 }
 
 impl JigsawTile {
     pub fn matrix(&self) -> Mat3<f32> {
-        Mat3::translate(self.interpolated.get())
+        Mat3::translate(self.interpolated.get()) * Mat3::rotate(self.angle)
     }
 
     pub fn contains(&self, pos: Vec2<f32>) -> bool {
-        let matrix = self.matrix();
-        for triangle in self.mesh.chunks(3) {
-            let triangle = [triangle[0], triangle[1], triangle[2]].map(|p| {
-                let p = matrix * p.a_pos.extend(1.0);
-                p.xy() / p.z
-            });
-            if util::triangle_contains(triangle, pos) {
-                return true;
-            }
-        }
-        false
+        let pos = self.matrix().inverse() * pos.extend(1.0);
+        self.geometry.contains(pos.xy() / pos.z)
     }
 }
@@ -0,0 +1,152 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    ZoomIn,
+    ZoomOut,
+    ZoomToFit,
+    FocusLastPiece,
+    RotateLeft,
+    RotateRight,
+    Flip,
+    ToggleEdges,
+    GatherEdges,
+    ToggleBoxLid,
+    TrayTool,
+    RemoveTray,
+    ClearSelection,
+    ToggleMinimap,
+    ToggleMute,
+    Ping,
+    /// Only together with Ctrl, with Shift as well it undoes the hovered piece's last move.
+    Undo,
+}
+
+impl Action {
+    pub const ALL: [Self; 21] = [
+        Self::PanUp,
+        Self::PanDown,
+        Self::PanLeft,
+        Self::PanRight,
+        Self::ZoomIn,
+        Self::ZoomOut,
+        Self::ZoomToFit,
+        Self::FocusLastPiece,
+        Self::RotateLeft,
+        Self::RotateRight,
+        Self::Flip,
+        Self::ToggleEdges,
+        Self::GatherEdges,
+        Self::ToggleBoxLid,
+        Self::TrayTool,
+        Self::RemoveTray,
+        Self::ClearSelection,
+        Self::ToggleMinimap,
+        Self::ToggleMute,
+        Self::Ping,
+        Self::Undo,
+    ];
+
+    fn default_keys(self) -> Vec<geng::Key> {
+        use geng::Key;
+        match self {
+            Self::PanUp => vec![Key::W, Key::Up],
+            Self::PanDown => vec![Key::S, Key::Down],
+            Self::PanLeft => vec![Key::A, Key::Left],
+            Self::PanRight => vec![Key::D, Key::Right],
+            Self::ZoomIn => vec![Key::Equals, Key::NumpadPlus],
+            Self::ZoomOut => vec![Key::Minus, Key::NumpadMinus],
+            Self::ZoomToFit => vec![Key::Home],
+            Self::FocusLastPiece => vec![Key::L],
+            Self::RotateLeft => vec![Key::Q],
+            Self::RotateRight => vec![Key::E],
+            Self::Flip => vec![Key::F],
+            Self::ToggleEdges => vec![Key::H],
+            Self::GatherEdges => vec![Key::G],
+            Self::ToggleBoxLid => vec![Key::B],
+            Self::TrayTool => vec![Key::T],
+            Self::RemoveTray => vec![Key::Delete],
+            Self::ClearSelection => vec![Key::Escape],
+            Self::ToggleMinimap => vec![Key::M],
+            Self::ToggleMute => vec![Key::N],
+            Self::Ping => vec![Key::P],
+            Self::Undo => vec![Key::Z],
+        }
+    }
+}
+
+/// Keys bound to each action, saved in the preferences.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keymap {
+    bindings: HashMap<Action, Vec<geng::Key>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            bindings: Action::ALL
+                .into_iter()
+                .map(|action| (action, action.default_keys()))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    const PREFERENCES_KEY: &'static str = "keymap";
+
+    pub fn load() -> Self {
+        let mut keymap: Self = batbox::preferences::load(Self::PREFERENCES_KEY).unwrap_or_default();
+        // Actions added after the keymap was saved get their default keys
+        for action in Action::ALL {
+            keymap
+                .bindings
+                .entry(action)
+                .or_insert_with(|| action.default_keys());
+        }
+        keymap
+    }
+
+    pub fn save(&self) {
+        batbox::preferences::save(Self::PREFERENCES_KEY, self);
+    }
+
+    pub fn keys(&self, action: Action) -> &[geng::Key] {
+        self.bindings.get(&action).map_or(&[], |keys| keys)
+    }
+
+    pub fn action(&self, key: geng::Key) -> Option<Action> {
+        Action::ALL
+            .into_iter()
+            .find(|&action| self.keys(action).contains(&key))
+    }
+
+    pub fn is_pressed(&self, window: &geng::Window, action: Action) -> bool {
+        self.keys(action)
+            .iter()
+            .any(|&key| window.is_key_pressed(key))
+    }
+
+    /// Makes the key the only one for the action, taking it away from other actions.
+    pub fn bind(&mut self, action: Action, key: geng::Key) {
+        for keys in self.bindings.values_mut() {
+            keys.retain(|&other| other != key);
+        }
+        self.bindings.insert(action, vec![key]);
+    }
+}
+
+#[test]
+fn test_keymap_bind() {
+    let mut keymap = Keymap::default();
+    assert_eq!(keymap.action(geng::Key::W), Some(Action::PanUp));
+    keymap.bind(Action::Flip, geng::Key::W);
+    assert_eq!(keymap.action(geng::Key::W), Some(Action::Flip));
+    assert_eq!(keymap.keys(Action::PanUp), &[geng::Key::Up]);
+    assert!(keymap.action(geng::Key::F).is_none());
+    assert_eq!(keymap.action(geng::Key::Minus), Some(Action::ZoomOut));
+}
@@ -5,17 +5,25 @@ mod game;
 mod interop;
 mod interpolation;
 mod jigsaw;
+mod keymap;
 mod main_menu;
+mod progress_bar;
 #[cfg(not(target_arch = "wasm32"))]
 mod server;
+mod settings;
 mod slider;
 mod splitscreen;
+#[cfg(not(target_arch = "wasm32"))]
+mod svg;
 mod text_input;
 mod util;
 
 use assets::Assets;
 use interop::*;
 use interpolation::*;
+use keymap::{Action, Keymap};
+use progress_bar::*;
+use settings::Settings;
 use slider::*;
 use text_input::*;
 
@@ -35,6 +43,15 @@ struct Opt {
     pub room_config: Option<std::path::PathBuf>,
     #[clap(long)]
     pub name: Option<String>,
+    /// Export cut lines of the puzzle from --room-config into an svg file
+    #[clap(long)]
+    pub export_svg: Option<std::path::PathBuf>,
+    /// Physical height of the exported puzzle in millimeters
+    #[clap(long, default_value = "200")]
+    pub svg_height: f32,
+    /// Embed the puzzle image into the exported svg
+    #[clap(long)]
+    pub svg_embed_image: bool,
 }
 
 fn main() {
@@ -42,6 +59,21 @@ fn main() {
     geng::setup_panic_handler();
     let mut opt: Opt = program_args::parse();
 
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(path) = &opt.export_svg {
+        let result = opt
+            .room_config
+            .as_ref()
+            .ok_or(svg::ExportError::MissingConfig)
+            .and_then(|config| svg::load_config(config))
+            .and_then(|config| svg::export(&config, path, opt.svg_height, opt.svg_embed_image));
+        if let Err(error) = result {
+            eprintln!("Failed to export svg: {error}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if opt.connect.is_none() && opt.server.is_none() {
         if cfg!(target_arch = "wasm32") {
             opt.connect = Some(
@@ -87,6 +119,10 @@ fn main() {
                     Some(ServerMessage::RoomCreated(name)) => {
                         opt.room = Some(name);
                     }
+                    // Fall back to the main menu to configure the room there
+                    Some(ServerMessage::InvalidRoomConfig) => {
+                        error!("The server rejected the room config");
+                    }
                     _ => unreachable!(),
                 }
             });
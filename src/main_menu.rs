@@ -5,6 +5,8 @@ struct ConfigScreen {
     geng: Geng,
     config: RoomConfig,
     addr: String,
+    /// Why the last room could not be created.
+    error: Option<String>,
     transition: Option<geng::Transition>,
     texture: ugli::Texture,
 }
@@ -20,7 +22,13 @@ impl ConfigScreen {
                 seed: thread_rng().gen(),
                 size: vec2(30, 1), // LUL
                 image: 0,
+                generator_version: jigsaw::GENERATOR_VERSION,
+                rotation: RotationMode::None,
+                double_sided: false,
+                hide_reference: false,
+                mode: GameMode::Cooperative,
             },
+            error: None,
             transition: None,
             texture,
         }
@@ -51,6 +59,8 @@ impl geng::State for ConfigScreen {
             let future = {
                 let geng = self.geng.clone();
                 let addr = self.addr.clone();
+                let assets = self.assets.clone();
+                let screen_config = self.config.clone();
                 let mut config = self.config.clone();
                 config.size = (1..=config.size.x)
                     .filter_map(|x| {
@@ -72,6 +82,13 @@ impl geng::State for ConfigScreen {
                     con.send(ClientMessage::CreateRoom(config));
                     let room = match con.next().await {
                         Some(ServerMessage::RoomCreated(name)) => name,
+                        Some(ServerMessage::InvalidRoomConfig) => {
+                            let mut screen = ConfigScreen::new(&geng, assets, &addr);
+                            screen.config = screen_config;
+                            screen.error =
+                                Some("The server could not create this puzzle".to_owned());
+                            return Box::new(screen) as Box<dyn geng::State>;
+                        }
                         _ => unreachable!(),
                     };
                     info!("room: {:?}", room);
@@ -81,7 +98,7 @@ impl geng::State for ConfigScreen {
                         .location()
                         .set_href(&format!("?room={}", room))
                         .unwrap();
-                    game::run(&geng, &addr, &room, None)
+                    Box::new(game::run(&geng, &addr, &room, None)) as Box<dyn geng::State>
                 }
             };
             let state =
@@ -105,9 +122,72 @@ impl geng::State for ConfigScreen {
                 + 1)
                 % options.len()];
         }
+        let rotation_button = Button::new(
+            cx,
+            match self.config.rotation {
+                RotationMode::None => "Rotation: NO",
+                RotationMode::Quarter => "Rotation: 90 degrees",
+                RotationMode::Free => "Rotation: FREE",
+            },
+        );
+        if rotation_button.was_clicked() {
+            self.config.rotation = match self.config.rotation {
+                RotationMode::None => RotationMode::Quarter,
+                RotationMode::Quarter => RotationMode::Free,
+                RotationMode::Free => RotationMode::None,
+            };
+        }
+        let double_sided_button = Button::new(
+            cx,
+            if self.config.double_sided {
+                "Face down pieces: YES"
+            } else {
+                "Face down pieces: NO"
+            },
+        );
+        if double_sided_button.was_clicked() {
+            self.config.double_sided = !self.config.double_sided;
+        }
+        let reference_button = Button::new(
+            cx,
+            if self.config.hide_reference {
+                "Box lid: NO"
+            } else {
+                "Box lid: YES"
+            },
+        );
+        if reference_button.was_clicked() {
+            self.config.hide_reference = !self.config.hide_reference;
+        }
+        let mode_button = Button::new(
+            cx,
+            match self.config.mode {
+                GameMode::Cooperative => "Mode: cooperative",
+                GameMode::Race => "Mode: race",
+                GameMode::Teams => "Mode: teams",
+            },
+        );
+        if mode_button.was_clicked() {
+            self.config.mode = match self.config.mode {
+                GameMode::Cooperative => GameMode::Race,
+                GameMode::Race => GameMode::Teams,
+                GameMode::Teams => GameMode::Cooperative,
+            };
+        }
+        let error = Text::new(
+            self.error.clone().unwrap_or_default(),
+            self.geng.default_font().clone(),
+            cx.theme().text_size,
+            Rgba::RED,
+        );
         (
+            error.center(),
             image_button.center(),
             difficulty_button.center(),
+            rotation_button.center(),
+            double_sided_button.center(),
+            reference_button.center(),
+            mode_button.center(),
             play_button.center(),
         )
             .column()
@@ -137,7 +217,14 @@ pub fn run(geng: &Geng, addr: &str) -> impl geng::State {
 }
 
 fn generate_background(geng: &Geng, assets: &Assets) -> ugli::Texture {
-    let mut jigsaw = jigsaw::Jigsaw::generate(geng.ugli(), 0, vec2(40.0, 30.0), vec2(40, 30));
+    let geometry = jigsaw::PuzzleGeometry::generate(
+        jigsaw::GENERATOR_VERSION,
+        0,
+        vec2(40.0, 30.0),
+        vec2(40, 30),
+    )
+    .expect("Failed to generate background jigsaw");
+    let mut jigsaw = jigsaw::Jigsaw::new(geng.ugli(), geometry, 1);
     let camera = geng::Camera2d {
         center: vec2(40.0, 30.0) / 2.0,
         rotation: 0.0,
@@ -151,11 +238,15 @@ fn generate_background(geng: &Geng, assets: &Assets) -> ugli::Texture {
             tile.interpolated
                 .teleport(tile.interpolated.get() * 1.05, Vec2::ZERO);
             let matrix = tile.matrix();
-            tile.mesh.iter().map(move |&(mut v)| {
-                let pos = matrix * v.a_pos.extend(1.0);
-                v.a_pos = pos.xy() / pos.z;
-                v
-            })
+            tile.geometry
+                .triangles
+                .iter()
+                .flatten()
+                .map(move |&(mut v)| {
+                    let pos = matrix * v.a_pos.extend(1.0);
+                    v.a_pos = pos.xy() / pos.z;
+                    v
+                })
         })
         .collect();
     let mesh = ugli::VertexBuffer::new_dynamic(geng.ugli(), mesh);
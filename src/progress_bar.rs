@@ -0,0 +1,63 @@
+use super::*;
+
+use geng::ui::*;
+
+/// Bar filled according to the progress, with a text on top.
+pub struct ProgressBar<'a> {
+    cx: &'a Controller,
+    text: String,
+    progress: f32,
+}
+
+impl<'a> ProgressBar<'a> {
+    pub fn new(cx: &'a Controller, text: String, progress: f32) -> Self {
+        Self { cx, text, progress }
+    }
+}
+
+impl<'a> Widget for ProgressBar<'a> {
+    fn draw(&mut self, cx: &mut DrawContext) {
+        let position = cx.position.map(|x| x as f32);
+        let filled = AABB {
+            x_max: position.x_min + position.width() * self.progress.clamp(0.0, 1.0),
+            ..position
+        };
+        cx.geng.draw_2d(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Quad::new(position, Rgba::new(0.0, 0.0, 0.0, 0.5)),
+        );
+        cx.geng.draw_2d(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Quad::new(
+                filled,
+                Rgba {
+                    a: 0.5,
+                    ..cx.theme.usable_color
+                },
+            ),
+        );
+        let font = cx.geng.default_font();
+        let size = position.height();
+        font.draw(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &self.text,
+            vec2(
+                position.center().x,
+                position.center().y - size * 0.5 - font.descender() * size,
+            ),
+            geng::TextAlign::CENTER,
+            size,
+            cx.theme.usable_color,
+        );
+    }
+
+    fn calc_constraints(&mut self, _children: &ConstraintsContext) -> Constraints {
+        Constraints {
+            min_size: vec2(300.0, self.cx.theme().text_size as f64),
+            flex: vec2(0.0, 0.0),
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use super::*;
 
+use jigsaw::PuzzleGeometry;
+
+use std::collections::VecDeque;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 struct IdGen {
     next_id: u64,
@@ -20,7 +24,12 @@ impl IdGen {
 struct Player {
     id: Id,
     room: String,
-    name: String,
+    profile: PlayerProfile,
+    /// Index of the board in the room the player works on.
+    board: usize,
+    /// Team of the player in team mode.
+    team: Option<usize>,
+    last_ping: Option<std::time::Instant>,
     sender: Box<dyn geng::net::Sender<ServerMessage>>,
 }
 
@@ -38,12 +47,393 @@ struct State {
     rooms: Collection<Room>,
 }
 
+const MAX_TRAYS: usize = 32;
+/// Maximum number of racers, each with their own board, in a room.
+const MAX_BOARDS: usize = 16;
+/// Minimum time between pings of a player.
+const PING_COOLDOWN: std::time::Duration = std::time::Duration::from_secs(1);
+/// Number of chat messages sent to players joining a room.
+const CHAT_HISTORY: usize = 50;
+
+/// Maximum number of moves remembered per room for undo.
+const UNDO_HISTORY: usize = 200;
+
 #[derive(HasId)]
 struct Room {
     #[has_id(id)]
     name: String,
-    tiles: Vec<TileState>,
     config: RoomConfig,
+    geometry: PuzzleGeometry,
+    /// Pieces as they were spread at the start, to give racers the same layout.
+    initial_tiles: Vec<TileState>,
+    /// A single board shared by everyone, or one per player in a race.
+    boards: Vec<Board>,
+    /// The first player to join, can undo moves of other players.
+    owner: Option<Id>,
+    next_tray_id: u64,
+    chat: VecDeque<ChatMessage>,
+    /// Room created for the next puzzle once this one was finished.
+    next_room: Option<String>,
+}
+
+/// One copy of the puzzle pieces.
+struct Board {
+    tiles: Vec<TileState>,
+    /// The racer working on this board.
+    owner: Option<Id>,
+    /// Name of the racer, to give the board back to them when they reconnect.
+    owner_name: String,
+    /// Trays only cover the pieces of the board they were made on.
+    trays: Vec<Tray>,
+    history: VecDeque<Move>,
+    /// Connections of the held tiles at the time they were grabbed,
+    /// so a move that connected them is never undone.
+    grab_connections: HashMap<usize, usize>,
+    first_move: Option<std::time::Instant>,
+    /// One per copy of the puzzle, so each team finishes on its own.
+    copies: Vec<CopyProgress>,
+}
+
+struct CopyProgress {
+    /// Number of groups of connected tiles.
+    groups: usize,
+    player_stats: Vec<PlayerStats>,
+    /// Player who made the last connection, and how many in a row.
+    streak: Option<(Id, usize)>,
+    finished: Option<PuzzleStats>,
+}
+
+/// Tiles moved by a single release of a player.
+struct Move {
+    player: Id,
+    tiles: Vec<TileMove>,
+}
+
+struct TileMove {
+    tile: usize,
+    from: Vec2<f32>,
+    to: Vec2<f32>,
+    /// Number of connections before the move, the move can not be undone once it changes.
+    connections: usize,
+}
+
+impl Room {
+    fn progress(&self, board: usize, copy: usize) -> Progress {
+        let board = &self.boards[board];
+        Progress {
+            pieces: self.geometry.tiles.len(),
+            groups: board.copies[copy].groups,
+            since_first_move: board
+                .first_move
+                .map(|first_move| first_move.elapsed().as_secs_f64()),
+            finish_time: board.copies[copy].finished.as_ref().map(|stats| stats.time),
+        }
+    }
+    /// Which copy of the puzzle the tile belongs to, same as the team owning it.
+    fn copy_of(&self, tile: usize) -> usize {
+        tile / self.geometry.tiles.len()
+    }
+    fn copy_origin(&self, copy: usize) -> Vec2<f32> {
+        jigsaw::copy_origin(self.geometry.size, copy, self.config.mode.copies())
+    }
+    /// Whether a player of the team may move the tile.
+    fn can_touch(&self, team: Option<usize>, tile: usize) -> bool {
+        self.config.mode != GameMode::Teams || team == Some(self.copy_of(tile))
+    }
+    /// Whether a private tray of another player holds the position.
+    fn protects(&self, board: usize, player: Id, pos: Vec2<f32>) -> bool {
+        self.boards[board]
+            .trays
+            .iter()
+            .any(|tray| tray.protects(player, pos))
+    }
+    /// Whether a player of the team may put a tray there,
+    /// in team mode only over their own half of the table.
+    fn can_place_tray(&self, team: Option<usize>, area: AABB<f32>) -> bool {
+        if self.config.mode != GameMode::Teams {
+            return true;
+        }
+        let team = match team {
+            Some(team) => team,
+            None => return false,
+        };
+        let origin = self.copy_origin(team).x;
+        let half = self.geometry.size.x;
+        (team == 0 || area.x_min >= origin - half)
+            && (team + 1 == self.config.mode.copies() || area.x_max <= origin + half)
+    }
+    /// Area covered by the puzzle and the pieces spread around it at the start.
+    fn table(&self) -> AABB<f32> {
+        let puzzle = AABB::ZERO.extend_symmetric(self.geometry.size / 2.0);
+        match util::bounding_box(self.initial_tiles.iter().map(|tile| tile.pos)) {
+            Some(spawned) => util::aabb_union(puzzle, spawned),
+            None => puzzle,
+        }
+    }
+    fn are_adjacent(&self, a: usize, b: usize) -> bool {
+        let n = self.geometry.tiles.len();
+        let tiles = n * self.config.mode.copies();
+        a < tiles
+            && b < tiles
+            && self.copy_of(a) == self.copy_of(b)
+            && self.geometry.are_adjacent(a % n, b % n)
+    }
+}
+
+impl Board {
+    fn new(tiles: Vec<TileState>, copies: usize, owner: Option<Id>, owner_name: String) -> Self {
+        Self {
+            copies: (0..copies)
+                .map(|_| CopyProgress {
+                    groups: tiles.len() / copies,
+                    player_stats: Vec::new(),
+                    streak: None,
+                    finished: None,
+                })
+                .collect(),
+            tiles,
+            owner,
+            owner_name,
+            trays: Vec::new(),
+            history: VecDeque::new(),
+            grab_connections: HashMap::new(),
+            first_move: None,
+        }
+    }
+    fn record_move(&mut self, player: Id, tiles: Vec<TileMove>) {
+        if self.history.len() >= UNDO_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(Move { player, tiles });
+    }
+    /// Whether the move can still be reverted, i.e. its tiles have not been
+    /// grabbed, moved or connected since.
+    fn can_undo(&self, entry: &Move) -> bool {
+        entry.tiles.iter().all(|moved| {
+            let tile = &self.tiles[moved.tile];
+            tile.grabbed_by.is_none()
+                && tile.pos == moved.to
+                && tile.connections.len() == moved.connections
+        })
+    }
+}
+
+impl CopyProgress {
+    fn count_connection(&mut self, player: &Player) {
+        let streak = match self.streak {
+            Some((id, streak)) if id == player.id => streak + 1,
+            _ => 1,
+        };
+        self.streak = Some((player.id, streak));
+        let index = match self
+            .player_stats
+            .iter()
+            .position(|stats| stats.player == player.id)
+        {
+            Some(index) => index,
+            None => {
+                self.player_stats.push(PlayerStats {
+                    player: player.id,
+                    name: String::new(),
+                    color: Rgba::WHITE,
+                    connected: 0,
+                    longest_streak: 0,
+                });
+                self.player_stats.len() - 1
+            }
+        };
+        let stats = &mut self.player_stats[index];
+        stats.name = player.profile.name.clone();
+        stats.color = player.profile.color;
+        stats.connected += 1;
+        stats.longest_streak = stats.longest_streak.max(streak);
+    }
+}
+
+fn generate_geometry(config: &RoomConfig) -> Option<PuzzleGeometry> {
+    let image = match std::fs::read(assets::image_path(config.image)) {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("Failed to read image #{}: {e}", config.image);
+            return None;
+        }
+    };
+    let image_size = match util::image_size(&image) {
+        Some(size) => size,
+        None => {
+            warn!("Failed to read the size of image #{}", config.image);
+            return None;
+        }
+    };
+    match PuzzleGeometry::from_room_config(config, image_size) {
+        Ok(geometry) => Some(geometry),
+        Err(e) => {
+            warn!("Failed to generate jigsaw: {e}");
+            None
+        }
+    }
+}
+
+fn connected_tiles(tiles: &[TileState], tile: usize) -> HashSet<usize> {
+    let mut connected = HashSet::new();
+    let mut queue = vec![tile];
+    while let Some(tile) = queue.pop() {
+        if connected.insert(tile) {
+            queue.extend(tiles[tile].connections.iter().copied());
+        }
+    }
+    connected
+}
+
+/// Grid of spots to gather loose pieces in, filling the table above the assembled
+/// puzzle area first and then below it.
+fn gather_positions(
+    geometry: &PuzzleGeometry,
+    center: Vec2<f32>,
+    table: AABB<f32>,
+) -> impl Iterator<Item = Vec2<f32>> {
+    // Leave room for the knobs and any rotation
+    let spacing = geometry.tile_size.x.max(geometry.tile_size.y) * 1.5;
+    let columns = ((geometry.size.x / spacing) as usize).max(1);
+    let left = center.x - geometry.size.x / 2.0 + spacing / 2.0;
+    let above = center.y + geometry.size.y / 2.0 + spacing;
+    let below = center.y - geometry.size.y / 2.0 - spacing;
+    let rows_above = ((table.y_max - above) / spacing).max(0.0) as usize + 1;
+    let rows_below = ((below - table.y_min) / spacing).max(0.0) as usize + 1;
+    (0..).map(move |i| {
+        // Once the table is full the rows start overlapping from the top again
+        let row = i / columns % (rows_above + rows_below);
+        let y = if row < rows_above {
+            above + row as f32 * spacing
+        } else {
+            below - (row - rows_above) as f32 * spacing
+        };
+        vec2(left + (i % columns) as f32 * spacing, y)
+    })
+}
+
+fn group_bounds(geometry: &PuzzleGeometry, tiles: &[TileState], group: &[usize]) -> AABB<f32> {
+    let n = geometry.tiles.len();
+    group
+        .iter()
+        .map(|&tile| geometry.tile_bounds(tile % n, tiles[tile].pos, tiles[tile].angle))
+        .reduce(util::aabb_union)
+        .unwrap()
+}
+
+/// Lays out the tile groups around the given area without overlapping it or each other.
+fn spread_groups(
+    geometry: &PuzzleGeometry,
+    tiles: &[TileState],
+    groups: &[Vec<usize>],
+    area: AABB<f32>,
+) -> Vec<TileMove> {
+    let boxes: Vec<AABB<f32>> = groups
+        .iter()
+        .map(|group| group_bounds(geometry, tiles, group))
+        .collect();
+    let gap = geometry.tile_size.x.max(geometry.tile_size.y) * jigsaw::SPREAD_GAP;
+    let corners = jigsaw::spread(area.size(), gap, &boxes);
+    let mut moves = Vec::new();
+    for ((group, aabb), corner) in groups.iter().zip(boxes).zip(corners) {
+        let delta = area.center() + corner - aabb.bottom_left();
+        moves.extend(group.iter().map(|&tile| TileMove {
+            tile,
+            from: tiles[tile].pos,
+            to: tiles[tile].pos + delta,
+            connections: tiles[tile].connections.len(),
+        }));
+    }
+    moves
+}
+
+/// Positions from clients end up in layouts, which can not handle NaN or infinity.
+fn valid_pos(pos: Vec2<f32>) -> bool {
+    pos.x.is_finite() && pos.y.is_finite()
+}
+
+fn valid_tray_area(area: AABB<f32>) -> bool {
+    [area.x_min, area.x_max, area.y_min, area.y_max]
+        .iter()
+        .all(|x| x.is_finite())
+        && area.width() >= Tray::MIN_SIZE
+        && area.height() >= Tray::MIN_SIZE
+}
+
+fn send_to_room(players: &mut Collection<Player>, room: &str, message: ServerMessage) {
+    for player in players {
+        if player.room == room {
+            player.sender.send(message.clone());
+        }
+    }
+}
+
+fn send_to_board(
+    players: &mut Collection<Player>,
+    room: &str,
+    board: usize,
+    message: ServerMessage,
+) {
+    for player in players {
+        if player.room == room && player.board == board {
+            player.sender.send(message.clone());
+        }
+    }
+}
+
+/// Tells everyone working on the board how far their team got.
+fn send_progress(players: &mut Collection<Player>, room: &Room, board: usize) {
+    for player in players {
+        if player.room == room.name && player.board == board {
+            let copy = player.team.unwrap_or(0);
+            player
+                .sender
+                .send(ServerMessage::Progress(room.progress(board, copy)));
+        }
+    }
+}
+
+/// Tells everyone in a race how far each racer still in the room got.
+fn send_leaderboard(players: &mut Collection<Player>, room: &Room) {
+    if room.config.mode != GameMode::Race {
+        return;
+    }
+    let entries = room
+        .boards
+        .iter()
+        .enumerate()
+        .filter_map(|(index, board)| {
+            let player = players.get(&board.owner?)?;
+            (player.room == room.name).then(|| RaceEntry {
+                player: player.id,
+                name: player.profile.name.clone(),
+                color: player.profile.color,
+                progress: room.progress(index, 0),
+            })
+        })
+        .collect();
+    send_to_room(players, &room.name, ServerMessage::Leaderboard(entries));
+}
+
+/// Tells everyone working on the board about tiles moved by the player.
+fn send_positions(
+    players: &mut Collection<Player>,
+    room: &str,
+    board: usize,
+    id: Id,
+    positions: &[(usize, Vec2<f32>)],
+) {
+    for player in players {
+        if player.room == room && player.board == board {
+            for &(tile, pos) in positions {
+                player.sender.send(ServerMessage::TileReleased {
+                    player: id,
+                    tile,
+                    pos,
+                });
+            }
+        }
+    }
 }
 
 impl State {
@@ -54,80 +444,254 @@ impl State {
             rooms: Collection::new(),
         }
     }
-    fn handle(&mut self, id: Id, message: ClientMessage) {
-        let room = self.players.get(&id).unwrap().room.clone();
-        match message {
-            ClientMessage::CreateRoom(config) => loop {
-                let name = create_room();
-                if self.rooms.get(&name).is_some() {
-                    warn!("Rng room name collision");
-                    continue;
-                } else {
-                    let player = self.players.get_mut(&id).unwrap();
-                    let mut rng = thread_rng();
-                    let bounds = AABB::ZERO.extend_uniform(3.0);
-                    let spawn_area = AABB::point(bounds.bottom_left())
-                        .extend_positive(vec2(bounds.width(), 3.0));
-                    let tiles = (0..config.size.x * config.size.y)
-                        .map(|_| {
-                            let pos = vec2(
-                                rng.gen_range(spawn_area.x_min..=spawn_area.x_max),
-                                rng.gen_range(spawn_area.y_min..=spawn_area.y_max),
-                            );
-                            TileState {
-                                grabbed_by: None,
-                                pos,
-                                connections: Vec::new(),
-                            }
-                        })
+    /// Creates a room with the pieces spread around the table and returns its name.
+    fn create_room(&mut self, config: RoomConfig) -> Option<String> {
+        let geometry = generate_geometry(&config)?;
+        loop {
+            let name = create_room();
+            if self.rooms.get(&name).is_some() {
+                warn!("Rng room name collision");
+                continue;
+            } else {
+                let mut rng = thread_rng();
+                let pieces = config.size.x * config.size.y;
+                let copies = config.mode.copies();
+                let tiles: Vec<TileState> = (0..pieces)
+                    .map(|_| TileState {
+                        grabbed_by: None,
+                        pos: Vec2::ZERO,
+                        angle: config.rotation.random_angle(&mut rng),
+                        flipped: config.double_sided && rng.gen(),
+                        connections: Vec::new(),
+                    })
+                    .collect();
+                // Every copy starts out the same, so no team gets an easier layout
+                let mut tiles: Vec<TileState> = tiles
+                    .iter()
+                    .cycle()
+                    .take(pieces * copies)
+                    .cloned()
+                    .collect();
+                let mut order: Vec<usize> = (0..pieces).collect();
+                order.shuffle(&mut rng);
+                for copy in 0..copies {
+                    let groups: Vec<Vec<usize>> = order
+                        .iter()
+                        .map(|&tile| vec![copy * pieces + tile])
                         .collect();
-                    self.rooms.insert(Room {
-                        name: name.clone(),
-                        tiles,
-                        config,
-                    });
-                    player.sender.send(ServerMessage::RoomCreated(name));
-                    break;
+                    let area = AABB::point(jigsaw::copy_origin(geometry.size, copy, copies))
+                        .extend_symmetric(geometry.size / 2.0);
+                    for moved in spread_groups(&geometry, &tiles, &groups, area) {
+                        tiles[moved.tile].pos = moved.to;
+                    }
                 }
-            },
+                // Racers get their own boards as they join
+                let boards = match config.mode {
+                    GameMode::Race => Vec::new(),
+                    _ => vec![Board::new(tiles.clone(), copies, None, String::new())],
+                };
+                self.rooms.insert(Room {
+                    name: name.clone(),
+                    config,
+                    geometry,
+                    initial_tiles: tiles,
+                    boards,
+                    owner: None,
+                    next_tray_id: 0,
+                    chat: VecDeque::new(),
+                    next_room: None,
+                });
+                return Some(name);
+            }
+        }
+    }
+    fn handle(&mut self, id: Id, message: ClientMessage) {
+        let player = self.players.get(&id).unwrap();
+        let room = player.room.clone();
+        let board = player.board;
+        let team = player.team;
+        match message {
+            ClientMessage::CreateRoom(config) => {
+                let message = match self.create_room(config) {
+                    Some(name) => ServerMessage::RoomCreated(name),
+                    None => ServerMessage::InvalidRoomConfig,
+                };
+                self.players.get_mut(&id).unwrap().sender.send(message);
+            }
+            ClientMessage::NewPuzzle => {
+                let (config, next_room) = match self.rooms.get(&room) {
+                    Some(room)
+                        if room.boards[board].copies[team.unwrap_or(0)]
+                            .finished
+                            .is_some() =>
+                    {
+                        (room.config.clone(), room.next_room.clone())
+                    }
+                    _ => return,
+                };
+                // Everyone may click the button, but only one room is created
+                let next_room = match next_room {
+                    Some(next_room) => next_room,
+                    None => {
+                        let config = RoomConfig {
+                            seed: thread_rng().gen(),
+                            generator_version: jigsaw::GENERATOR_VERSION,
+                            ..config
+                        };
+                        match self.create_room(config) {
+                            Some(next_room) => next_room,
+                            None => return,
+                        }
+                    }
+                };
+                self.rooms.get_mut(&room).unwrap().next_room = Some(next_room.clone());
+                send_to_room(
+                    &mut self.players,
+                    &room,
+                    ServerMessage::MoveToRoom(next_room),
+                );
+            }
             ClientMessage::UpdatePos(pos) => {
+                if !valid_pos(pos) {
+                    return;
+                }
                 if let Some(room) = self.rooms.get_mut(&room) {
                     for player in &mut self.players {
-                        if player.id != id && player.room == room.name {
+                        if player.id != id && player.room == room.name && player.board == board {
                             player.sender.send(ServerMessage::UpdatePos(id, pos));
                         }
                     }
                 }
             }
-            ClientMessage::UpdateName(name) => {
-                self.players.get_mut(&id).unwrap().name = name.clone();
+            ClientMessage::UpdateProfile(mut profile) => {
+                profile.name = util::truncate_graphemes(&profile.name, NAME_MAX).to_owned();
+                self.players.get_mut(&id).unwrap().profile = profile.clone();
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    if room.boards[board].owner == Some(id) {
+                        room.boards[board].owner_name = profile.name.clone();
+                    }
+                }
                 for player in &mut self.players {
-                    if player.id != id && player.room == room {
+                    if player.id != id && player.room == room && player.board == board {
                         player
                             .sender
-                            .send(ServerMessage::UpdatePlayerName(id, name.clone()));
+                            .send(ServerMessage::UpdatePlayerProfile(id, profile.clone()));
                     }
                 }
+                if let Some(room) = self.rooms.get(&room) {
+                    send_leaderboard(&mut self.players, room);
+                }
             }
-            ClientMessage::SelectRoom(room) => {
-                let player = self.players.get_mut(&id).unwrap();
+            ClientMessage::SelectRoom { room, name } => {
+                let name = util::truncate_graphemes(&name, NAME_MAX).to_owned();
                 let mut messages = Vec::new();
-                if let Some(room) = self.rooms.get(&room) {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    let board = match room.config.mode {
+                        GameMode::Race => {
+                            let players = &self.players;
+                            // A racer coming back continues on their own board
+                            let reused = room.boards.iter().position(|board| {
+                                board.owner == Some(id)
+                                    || (!name.is_empty()
+                                        && board.owner_name == name
+                                        && board.owner.is_none_or(|owner| {
+                                            players
+                                                .get(&owner)
+                                                .is_none_or(|owner| owner.room != room.name)
+                                        }))
+                            });
+                            match reused {
+                                Some(index) => {
+                                    room.boards[index].owner = Some(id);
+                                    index
+                                }
+                                None if room.boards.len() < MAX_BOARDS => {
+                                    room.boards.push(Board::new(
+                                        room.initial_tiles.clone(),
+                                        1,
+                                        Some(id),
+                                        name.clone(),
+                                    ));
+                                    room.boards.len() - 1
+                                }
+                                None => {
+                                    let player = self.players.get_mut(&id).unwrap();
+                                    player.sender.send(ServerMessage::RoomFull);
+                                    return;
+                                }
+                            }
+                        }
+                        _ => 0,
+                    };
+                    let others: Vec<&Player> = self
+                        .players
+                        .iter()
+                        .filter(|player| player.id != id && player.room == room.name)
+                        .collect();
+                    // First color nobody in the room has yet
+                    let color_index = (0..PLAYER_COLORS.len())
+                        .find(|&i| {
+                            others
+                                .iter()
+                                .all(|player| player.profile.color != player_color(i))
+                        })
+                        .unwrap_or(others.len());
+                    // Join the team with fewer players
+                    let team = (room.config.mode == GameMode::Teams).then(|| {
+                        (0..TEAMS.len())
+                            .min_by_key(|&team| {
+                                others
+                                    .iter()
+                                    .filter(|player| player.team == Some(team))
+                                    .count()
+                            })
+                            .unwrap()
+                    });
+                    // Racers only see the cursors of the players on their own board
+                    for player in others.into_iter().filter(|player| player.board == board) {
+                        messages.push(ServerMessage::UpdatePlayerProfile(
+                            player.id,
+                            player.profile.clone(),
+                        ));
+                        if let Some(team) = player.team {
+                            messages.push(ServerMessage::TeamAssigned {
+                                player: player.id,
+                                team,
+                            });
+                        }
+                    }
+                    let player = self.players.get_mut(&id).unwrap();
                     player.room = room.name.clone();
-                    player.sender.send(ServerMessage::SetupId {
+                    player.board = board;
+                    player.team = team;
+                    player.profile.color = player_color(color_index);
+                    player.profile.name = name;
+                    room.owner.get_or_insert(id);
+                    player.sender.send(ServerMessage::SetupId(RoomSetup {
                         player_id: id,
                         room_config: room.config.clone(),
-                        tiles: room.tiles.clone(),
-                    });
-                    for player in &self.players {
-                        if player.id != id && player.room == room.name {
-                            messages.push(ServerMessage::UpdatePlayerName(
-                                player.id,
-                                player.name.clone(),
-                            ));
+                        tiles: room.boards[board].tiles.clone(),
+                        trays: room.boards[board].trays.clone(),
+                        default_color: player.profile.color,
+                        chat: room.chat.iter().cloned().collect(),
+                        stats: room.boards[board].copies[team.unwrap_or(0)]
+                            .finished
+                            .clone(),
+                        progress: room.progress(board, team.unwrap_or(0)),
+                        team,
+                    }));
+                    if let Some(team) = team {
+                        for other in &mut self.players {
+                            if other.id != id && other.room == room.name {
+                                other
+                                    .sender
+                                    .send(ServerMessage::TeamAssigned { player: id, team });
+                            }
                         }
                     }
+                    send_leaderboard(&mut self.players, room);
                 } else {
+                    let player = self.players.get_mut(&id).unwrap();
                     player.sender.send(ServerMessage::RoomNotFound);
                 }
                 let player = self.players.get_mut(&id).unwrap(); // KEKW
@@ -135,32 +699,101 @@ impl State {
                     player.sender.send(message);
                 }
             }
-            ClientMessage::GrabTile {
-                tile: tile_id,
-                offset,
-            } => {
+            ClientMessage::GrabTiles(grabs) => {
                 if let Some(room) = self.rooms.get_mut(&room) {
-                    if let Some(tile) = room.tiles.get_mut(tile_id) {
-                        if tile.grabbed_by.is_none() {
-                            tile.grabbed_by = Some(id);
-                            for player in &mut self.players {
-                                if player.id != id && player.room == room.name {
-                                    player.sender.send(ServerMessage::TileGrabbed {
-                                        player: id,
-                                        tile: tile_id,
-                                        offset,
-                                    });
-                                }
+                    // Either all groups are grabbed, or none of them
+                    let available = grabs.iter().all(|&(tile_id, offset)| {
+                        valid_pos(offset)
+                            && tile_id < room.boards[board].tiles.len()
+                            && room.can_touch(team, tile_id)
+                            && connected_tiles(&room.boards[board].tiles, tile_id)
+                                .into_iter()
+                                .all(|tile| {
+                                    let tile = &room.boards[board].tiles[tile];
+                                    tile.grabbed_by.is_none() && !room.protects(board, id, tile.pos)
+                                })
+                    });
+                    if !available {
+                        self.players
+                            .get_mut(&id)
+                            .unwrap()
+                            .sender
+                            .send(ServerMessage::GrabDenied(
+                                grabs.iter().map(|&(tile, _)| tile).collect(),
+                            ));
+                        return;
+                    }
+                    for &(tile_id, _) in &grabs {
+                        room.boards[board].tiles[tile_id].grabbed_by = Some(id);
+                        for tile in connected_tiles(&room.boards[board].tiles, tile_id) {
+                            let connections = room.boards[board].tiles[tile].connections.len();
+                            room.boards[board]
+                                .grab_connections
+                                .insert(tile, connections);
+                        }
+                    }
+                    if room.boards[board].first_move.is_none() {
+                        room.boards[board].first_move = Some(std::time::Instant::now());
+                        send_progress(&mut self.players, room, board);
+                        send_leaderboard(&mut self.players, room);
+                    }
+                    for player in &mut self.players {
+                        if player.id != id && player.room == room.name && player.board == board {
+                            for &(tile_id, offset) in &grabs {
+                                player.sender.send(ServerMessage::TileGrabbed {
+                                    player: id,
+                                    tile: tile_id,
+                                    offset,
+                                });
                             }
                         }
                     }
                 }
             }
             ClientMessage::ReleaseTile(updates) => {
+                if !updates.iter().all(|&(_, pos)| valid_pos(pos)) {
+                    warn!("Invalid position of released tiles");
+                    return;
+                }
                 if let Some(room) = self.rooms.get_mut(&room) {
-                    if let Some((tile_id, pos)) = updates.first().copied() {
-                        for player in &mut self.players {
-                            if player.id != id && player.room == room.name {
+                    // Only the groups held by the player can be moved
+                    let tiles = &room.boards[board].tiles;
+                    let held: HashSet<usize> = updates
+                        .iter()
+                        .filter(|&&(tile, _)| {
+                            tiles
+                                .get(tile)
+                                .is_some_and(|tile| tile.grabbed_by == Some(id))
+                        })
+                        .flat_map(|&(tile, _)| connected_tiles(tiles, tile))
+                        .collect();
+                    let mut released = Vec::new();
+                    let mut moved = Vec::new();
+                    for (tile_id, pos) in updates {
+                        if !held.contains(&tile_id) || !room.can_touch(team, tile_id) {
+                            continue;
+                        }
+                        let connections = room.boards[board].grab_connections.remove(&tile_id);
+                        if let Some(tile) = room.boards[board].tiles.get_mut(tile_id) {
+                            if tile.grabbed_by == Some(id) {
+                                tile.grabbed_by = None;
+                                released.push((tile_id, pos));
+                            }
+                            moved.push(TileMove {
+                                tile: tile_id,
+                                from: tile.pos,
+                                to: pos,
+                                connections: connections.unwrap_or(tile.connections.len()),
+                            });
+                            tile.pos = pos;
+                        }
+                    }
+                    if !released.is_empty() {
+                        room.boards[board].record_move(id, moved);
+                    }
+                    for player in &mut self.players {
+                        if player.id != id && player.room == room.name && player.board == board {
+                            for &(tile_id, pos) in &released {
                                 player.sender.send(ServerMessage::TileReleased {
                                     player: id,
                                     tile: tile_id,
@@ -169,24 +802,370 @@ impl State {
                             }
                         }
                     }
-                    for (tile_id, pos) in updates {
-                        if let Some(tile) = room.tiles.get_mut(tile_id) {
-                            tile.grabbed_by.take();
-                            tile.pos = pos;
+                }
+            }
+            ClientMessage::Undo(tile) => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    // The history is short enough to search for the player's last move
+                    let index = room.boards[board]
+                        .history
+                        .iter()
+                        .rposition(|entry| match tile {
+                            Some(tile) => entry.tiles.iter().any(|moved| moved.tile == tile),
+                            None => entry.player == id,
+                        });
+                    let index = match index {
+                        Some(index) => index,
+                        None => return,
+                    };
+                    if room.boards[board].history[index].player != id && room.owner != Some(id) {
+                        return;
+                    }
+                    let entry = &room.boards[board].history[index];
+                    // Stale moves can never be undone, so they are dropped
+                    if !room.boards[board].can_undo(entry) {
+                        room.boards[board].history.remove(index);
+                        return;
+                    }
+                    // A move blocked by a tray or another team stays, someone else may undo it
+                    if !entry.tiles.iter().all(|moved| {
+                        room.can_touch(team, moved.tile)
+                            && !room.protects(board, id, room.boards[board].tiles[moved.tile].pos)
+                    }) {
+                        return;
+                    }
+                    let entry = room.boards[board].history.remove(index).unwrap();
+                    let positions: Vec<_> = entry
+                        .tiles
+                        .iter()
+                        .map(|moved| (moved.tile, moved.from))
+                        .collect();
+                    for &(tile, pos) in &positions {
+                        room.boards[board].tiles[tile].pos = pos;
+                    }
+                    send_positions(&mut self.players, &room.name, board, id, &positions);
+                }
+            }
+            ClientMessage::GatherEdges => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    let n = room.geometry.tiles.len();
+                    let loose_edges =
+                        room.boards[board]
+                            .tiles
+                            .iter()
+                            .enumerate()
+                            .filter(|&(i, tile)| {
+                                room.geometry.tiles[i % n].is_edge()
+                                    && room.can_touch(team, i)
+                                    && !room.protects(board, id, tile.pos)
+                                    && tile.connections.is_empty()
+                                    && tile.grabbed_by.is_none()
+                                    && !tile.flipped
+                            });
+                    let moved: Vec<TileMove> = loose_edges
+                        .zip(gather_positions(
+                            &room.geometry,
+                            room.copy_origin(team.unwrap_or(0)),
+                            room.table(),
+                        ))
+                        .map(|((i, tile), pos)| TileMove {
+                            tile: i,
+                            from: tile.pos,
+                            to: pos,
+                            connections: 0,
+                        })
+                        .collect();
+                    if moved.is_empty() {
+                        return;
+                    }
+                    let positions: Vec<_> =
+                        moved.iter().map(|moved| (moved.tile, moved.to)).collect();
+                    for &(tile, pos) in &positions {
+                        room.boards[board].tiles[tile].pos = pos;
+                    }
+                    room.boards[board].record_move(id, moved);
+                    send_positions(&mut self.players, &room.name, board, id, &positions);
+                }
+            }
+            ClientMessage::SpreadPieces => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    let mut groups = Vec::new();
+                    let mut checked = HashSet::new();
+                    for tile in 0..room.boards[board].tiles.len() {
+                        if checked.contains(&tile) {
+                            continue;
+                        }
+                        let group = connected_tiles(&room.boards[board].tiles, tile);
+                        checked.extend(group.iter().copied());
+                        if group.iter().all(|&tile| {
+                            let state = &room.boards[board].tiles[tile];
+                            state.grabbed_by.is_none()
+                                && room.can_touch(team, tile)
+                                && !room.protects(board, id, state.pos)
+                        }) {
+                            let mut group: Vec<usize> = group.into_iter().collect();
+                            group.sort();
+                            groups.push(group);
+                        }
+                    }
+                    let mut area = AABB::point(room.copy_origin(team.unwrap_or(0)))
+                        .extend_symmetric(room.geometry.size / 2.0);
+                    // The biggest group is the one being assembled, so it stays in place
+                    // and the others are spread around it
+                    if let Some(biggest) = groups
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, group)| group.len() > 1)
+                        .max_by_key(|(_, group)| group.len())
+                        .map(|(i, _)| i)
+                    {
+                        let kept = groups.remove(biggest);
+                        area = util::aabb_union(
+                            area,
+                            group_bounds(&room.geometry, &room.boards[board].tiles, &kept),
+                        );
+                    }
+                    let moved =
+                        spread_groups(&room.geometry, &room.boards[board].tiles, &groups, area);
+                    if moved.is_empty() {
+                        return;
+                    }
+                    let positions: Vec<_> =
+                        moved.iter().map(|moved| (moved.tile, moved.to)).collect();
+                    for &(tile, pos) in &positions {
+                        room.boards[board].tiles[tile].pos = pos;
+                    }
+                    room.boards[board].record_move(id, moved);
+                    send_positions(&mut self.players, &room.name, board, id, &positions);
+                }
+            }
+            ClientMessage::CreateTray {
+                name,
+                area,
+                private,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    if room.boards[board].trays.len() >= MAX_TRAYS
+                        || !valid_tray_area(area)
+                        || !room.can_place_tray(team, area)
+                    {
+                        return;
+                    }
+                    let tray = Tray {
+                        id: room.next_tray_id,
+                        name: util::truncate_graphemes(&name, TRAY_NAME_MAX).to_owned(),
+                        area,
+                        owner: private.then_some(id),
+                    };
+                    room.next_tray_id += 1;
+                    room.boards[board].trays.push(tray.clone());
+                    send_to_board(
+                        &mut self.players,
+                        &room.name,
+                        board,
+                        ServerMessage::TrayUpdated(tray),
+                    );
+                }
+            }
+            ClientMessage::ResizeTray { id: tray_id, area } => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    if !valid_tray_area(area) || !room.can_place_tray(team, area) {
+                        return;
+                    }
+                    if let Some(tray) = room.boards[board]
+                        .trays
+                        .iter_mut()
+                        .find(|tray| tray.id == tray_id && tray.can_edit(id))
+                    {
+                        tray.area = area;
+                        let tray = tray.clone();
+                        send_to_board(
+                            &mut self.players,
+                            &room.name,
+                            board,
+                            ServerMessage::TrayUpdated(tray),
+                        );
+                    }
+                }
+            }
+            ClientMessage::RemoveTray(tray_id) => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    let trays = &mut room.boards[board].trays;
+                    let len = trays.len();
+                    trays.retain(|tray| tray.id != tray_id || !tray.can_edit(id));
+                    if trays.len() != len {
+                        send_to_board(
+                            &mut self.players,
+                            &room.name,
+                            board,
+                            ServerMessage::TrayRemoved(tray_id),
+                        );
+                    }
+                }
+            }
+            ClientMessage::Ping(pos) => {
+                if !valid_pos(pos) {
+                    return;
+                }
+                let player = self.players.get_mut(&id).unwrap();
+                let now = std::time::Instant::now();
+                if player
+                    .last_ping
+                    .is_some_and(|last| now - last < PING_COOLDOWN)
+                {
+                    return;
+                }
+                player.last_ping = Some(now);
+                if self.rooms.get(&room).is_some() {
+                    send_to_board(
+                        &mut self.players,
+                        &room,
+                        board,
+                        ServerMessage::Ping { player: id, pos },
+                    );
+                }
+            }
+            ClientMessage::Chat(text) => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    let text = util::truncate_graphemes(text.trim(), CHAT_MESSAGE_MAX).to_owned();
+                    if text.is_empty() {
+                        return;
+                    }
+                    let message = ChatMessage {
+                        player: id,
+                        name: self.players.get(&id).unwrap().profile.name.clone(),
+                        text,
+                        timestamp: std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map_or(0, |time| time.as_secs()),
+                    };
+                    if room.chat.len() >= CHAT_HISTORY {
+                        room.chat.pop_front();
+                    }
+                    room.chat.push_back(message.clone());
+                    send_to_room(&mut self.players, &room.name, ServerMessage::Chat(message));
+                }
+            }
+            ClientMessage::RotateTile {
+                tile: tile_id,
+                angle,
+            } => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    if room.config.rotation == RotationMode::None
+                        || !angle.is_finite()
+                        || room.boards[board]
+                            .tiles
+                            .get(tile_id)
+                            .map(|tile| tile.grabbed_by)
+                            != Some(Some(id))
+                    {
+                        return;
+                    }
+                    // Quarter turns must stay axis aligned
+                    let angle = room.config.rotation.round(angle);
+                    for tile in connected_tiles(&room.boards[board].tiles, tile_id) {
+                        room.boards[board].tiles[tile].angle = angle;
+                    }
+                    for player in &mut self.players {
+                        if player.id != id && player.room == room.name && player.board == board {
+                            player.sender.send(ServerMessage::TileRotated {
+                                player: id,
+                                tile: tile_id,
+                                angle,
+                            });
+                        }
+                    }
+                }
+            }
+            ClientMessage::FlipTile(tile_id) => {
+                if let Some(room) = self.rooms.get_mut(&room) {
+                    if !room.config.double_sided || !room.can_touch(team, tile_id) {
+                        return;
+                    }
+                    if let Some(tile) = room.boards[board].tiles.get_mut(tile_id) {
+                        if !tile.connections.is_empty()
+                            || tile.grabbed_by.is_some_and(|player| player != id)
+                        {
+                            return;
+                        }
+                        tile.flipped = !tile.flipped;
+                        let flipped = tile.flipped;
+                        for player in &mut self.players {
+                            if player.room == room.name && player.board == board {
+                                player.sender.send(ServerMessage::TileFlipped {
+                                    tile: tile_id,
+                                    flipped,
+                                });
+                            }
                         }
                     }
                 }
             }
             ClientMessage::ConnectTiles(a, b) => {
-                // TODO: check validity
                 if let Some(room) = self.rooms.get_mut(&room) {
-                    room.tiles[a].connections.push(b);
-                    room.tiles[b].connections.push(a);
+                    if !room.are_adjacent(a, b)
+                        || !room.can_touch(team, a)
+                        || room.boards[board].tiles[a].connections.contains(&b)
+                        || room.boards[board].tiles[a].flipped
+                        || room.boards[board].tiles[b].flipped
+                    {
+                        warn!("Invalid connection of tiles {a} and {b}");
+                        return;
+                    }
+                    // Tiles of the same group can be connected again along another edge
+                    let joins_groups = !connected_tiles(&room.boards[board].tiles, a).contains(&b);
+                    room.boards[board].tiles[a].connections.push(b);
+                    room.boards[board].tiles[b].connections.push(a);
+                    let group = connected_tiles(&room.boards[board].tiles, a);
+                    let angle = room.boards[board].tiles[b].angle;
+                    for &tile in &group {
+                        room.boards[board].tiles[tile].angle = angle;
+                    }
                     for player in &mut self.players {
-                        if player.room == room.name {
+                        if player.room == room.name && player.board == board {
                             player.sender.send(ServerMessage::ConnectTiles(a, b));
                         }
                     }
+                    if !joins_groups {
+                        return;
+                    }
+                    let copy = room.copy_of(a);
+                    let progress = &mut room.boards[board].copies[copy];
+                    progress.groups -= 1;
+                    progress.count_connection(self.players.get(&id).unwrap());
+                    if progress.finished.is_none() && group.len() == room.geometry.tiles.len() {
+                        let mut players = progress.player_stats.clone();
+                        players.sort_by_key(|stats| std::cmp::Reverse(stats.connected));
+                        // The other team keeps playing for its own time after losing
+                        let winning_team = (room.config.mode == GameMode::Teams).then(|| {
+                            room.boards[board]
+                                .copies
+                                .iter()
+                                .filter_map(|progress| progress.finished.as_ref())
+                                .find_map(|stats| stats.winning_team)
+                                .unwrap_or(copy)
+                        });
+                        let stats = PuzzleStats {
+                            time: room.boards[board]
+                                .first_move
+                                .map_or(0.0, |first_move| first_move.elapsed().as_secs_f64()),
+                            players,
+                            winning_team,
+                        };
+                        room.boards[board].copies[copy].finished = Some(stats.clone());
+                        for player in &mut self.players {
+                            if player.room == room.name
+                                && player.board == board
+                                && player.team.unwrap_or(0) == copy
+                            {
+                                player
+                                    .sender
+                                    .send(ServerMessage::PuzzleFinished(stats.clone()));
+                            }
+                        }
+                    }
+                    send_progress(&mut self.players, room, board);
+                    send_leaderboard(&mut self.players, room);
                 }
             }
         }
@@ -214,8 +1193,15 @@ impl geng::net::server::App for App {
         let id = state.id_gen.gen();
         let player = Player {
             id,
-            name: "".to_owned(),
+            profile: PlayerProfile {
+                name: "".to_owned(),
+                color: Rgba::WHITE,
+                cursor: CursorStyle::Hand,
+            },
             room: create_room(),
+            board: 0,
+            team: None,
+            last_ping: None,
             sender,
         };
         state.players.insert(player);
@@ -240,11 +1226,199 @@ impl geng::net::Receiver<ClientMessage> for Client {
 impl Drop for Client {
     fn drop(&mut self) {
         let mut state = self.state.lock().unwrap();
-        state.players.remove(&self.id);
+        let player = state.players.remove(&self.id).unwrap();
+        if let Some(room) = state.rooms.get(&player.room) {
+            if room.owner == Some(self.id) {
+                // Pass the ownership to someone still in the room
+                let owner = state
+                    .players
+                    .iter()
+                    .find(|other| other.room == room.name)
+                    .map(|other| other.id);
+                state.rooms.get_mut(&player.room).unwrap().owner = owner;
+            }
+        }
+        if let Some(room) = state.rooms.get_mut(&player.room) {
+            // Nobody could ever use the trays of a player that left
+            let mut updated = Vec::new();
+            for tray in &mut room.boards[player.board].trays {
+                if tray.owner == Some(self.id) {
+                    tray.owner = None;
+                    updated.push(tray.clone());
+                }
+            }
+            let room = room.name.clone();
+            for tray in updated {
+                send_to_board(
+                    &mut state.players,
+                    &room,
+                    player.board,
+                    ServerMessage::TrayUpdated(tray),
+                );
+            }
+        }
         for player in &mut state.players {
             player
                 .sender
                 .send(ServerMessage::PlayerDisconnected(self.id));
         }
+        let state = &mut *state;
+        if let Some(room) = state.rooms.get(&player.room) {
+            send_leaderboard(&mut state.players, room);
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct TestSender(Arc<Mutex<Vec<ServerMessage>>>);
+
+#[cfg(test)]
+impl geng::net::Sender<ServerMessage> for TestSender {
+    fn send(&mut self, message: ServerMessage) {
+        self.0.lock().unwrap().push(message);
     }
 }
+
+#[cfg(test)]
+impl TestSender {
+    fn take(&self) -> Vec<ServerMessage> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+fn test_room(app: &App, mode: GameMode) -> String {
+    let config = RoomConfig {
+        seed: 0,
+        size: vec2(3, 3),
+        image: 0,
+        generator_version: jigsaw::GENERATOR_VERSION,
+        rotation: default(),
+        double_sided: false,
+        hide_reference: false,
+        mode,
+    };
+    app.state.lock().unwrap().create_room(config).unwrap()
+}
+
+#[cfg(test)]
+fn test_join(app: &mut App, room: &str, name: &str) -> (Client, TestSender) {
+    use geng::net::Receiver;
+    let sender = TestSender::default();
+    let mut client = geng::net::server::App::connect(app, Box::new(sender.clone()));
+    client.handle(ClientMessage::SelectRoom {
+        room: room.to_owned(),
+        name: name.to_owned(),
+    });
+    (client, sender)
+}
+
+#[test]
+fn test_racers_do_not_see_each_other() {
+    use geng::net::Receiver;
+    let mut app = App::new();
+    let room = test_room(&app, GameMode::Race);
+    let (mut a, _) = test_join(&mut app, &room, "a");
+    let (_b, b_messages) = test_join(&mut app, &room, "b");
+    b_messages.take();
+    a.handle(ClientMessage::UpdateProfile(PlayerProfile {
+        name: "a".to_owned(),
+        color: Rgba::WHITE,
+        cursor: CursorStyle::Hand,
+    }));
+    a.handle(ClientMessage::UpdatePos(vec2(1.0, 1.0)));
+    a.handle(ClientMessage::GrabTiles(vec![(0, Vec2::ZERO)]));
+    for message in b_messages.take() {
+        assert!(!matches!(
+            message,
+            ServerMessage::UpdatePos(..)
+                | ServerMessage::UpdatePlayerProfile(..)
+                | ServerMessage::TileGrabbed { .. }
+        ));
+    }
+}
+
+#[cfg(test)]
+fn test_move(client: &mut Client, tile: usize, pos: Vec2<f32>) {
+    use geng::net::Receiver;
+    client.handle(ClientMessage::GrabTiles(vec![(tile, Vec2::ZERO)]));
+    client.handle(ClientMessage::ReleaseTile(vec![(tile, pos)]));
+}
+
+#[cfg(test)]
+fn test_tile_pos(app: &App, room: &str, tile: usize) -> Vec2<f32> {
+    let state = app.state.lock().unwrap();
+    state.rooms.get(&room.to_owned()).unwrap().boards[0].tiles[tile].pos
+}
+
+#[test]
+fn test_undo_own_move() {
+    use geng::net::Receiver;
+    let mut app = App::new();
+    let room = test_room(&app, GameMode::Cooperative);
+    let (_owner, _) = test_join(&mut app, &room, "owner");
+    let (mut a, _) = test_join(&mut app, &room, "a");
+    let (mut b, _) = test_join(&mut app, &room, "b");
+    let start = test_tile_pos(&app, &room, 0);
+    test_move(&mut a, 0, vec2(100.0, 100.0));
+
+    // Only the owner of the room may undo moves of other players
+    b.handle(ClientMessage::Undo(Some(0)));
+    assert_eq!(test_tile_pos(&app, &room, 0), vec2(100.0, 100.0));
+    a.handle(ClientMessage::Undo(None));
+    assert_eq!(test_tile_pos(&app, &room, 0), start);
+}
+
+#[test]
+fn test_undo_by_owner() {
+    use geng::net::Receiver;
+    let mut app = App::new();
+    let room = test_room(&app, GameMode::Cooperative);
+    let (mut owner, _) = test_join(&mut app, &room, "owner");
+    let (mut a, _) = test_join(&mut app, &room, "a");
+    let start = test_tile_pos(&app, &room, 0);
+    test_move(&mut a, 0, vec2(100.0, 100.0));
+    owner.handle(ClientMessage::Undo(Some(0)));
+    assert_eq!(test_tile_pos(&app, &room, 0), start);
+}
+
+#[test]
+fn test_undo_blocked_by_tray() {
+    use geng::net::Receiver;
+    let mut app = App::new();
+    let room = test_room(&app, GameMode::Cooperative);
+    let (mut owner, _) = test_join(&mut app, &room, "owner");
+    let (mut a, _) = test_join(&mut app, &room, "a");
+    let start = test_tile_pos(&app, &room, 0);
+    test_move(&mut a, 0, vec2(100.0, 100.0));
+    owner.handle(ClientMessage::CreateTray {
+        name: "mine".to_owned(),
+        area: AABB::point(vec2(100.0, 100.0)).extend_uniform(5.0),
+        private: true,
+    });
+    a.handle(ClientMessage::Undo(None));
+    assert_eq!(test_tile_pos(&app, &room, 0), vec2(100.0, 100.0));
+
+    // The blocked move is still there once the tray is gone
+    owner.handle(ClientMessage::RemoveTray(0));
+    a.handle(ClientMessage::Undo(None));
+    assert_eq!(test_tile_pos(&app, &room, 0), start);
+}
+
+#[test]
+fn test_undo_other_team() {
+    use geng::net::Receiver;
+    let mut app = App::new();
+    let room = test_room(&app, GameMode::Teams);
+    let (mut owner, _) = test_join(&mut app, &room, "owner");
+    let (mut a, _) = test_join(&mut app, &room, "a");
+    // The second player joins the second team, which owns the second copy
+    let tile = 9;
+    let start = test_tile_pos(&app, &room, tile);
+    test_move(&mut a, tile, vec2(100.0, 100.0));
+    owner.handle(ClientMessage::Undo(Some(tile)));
+    assert_eq!(test_tile_pos(&app, &room, tile), vec2(100.0, 100.0));
+    a.handle(ClientMessage::Undo(None));
+    assert_eq!(test_tile_pos(&app, &room, tile), start);
+}
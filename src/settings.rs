@@ -0,0 +1,68 @@
+use super::*;
+
+/// Local preferences changed in the settings screen.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub music_volume: f64,
+    pub effects_volume: f64,
+    pub music_muted: bool,
+    pub effects_muted: bool,
+    /// Scale of the cursors of all players.
+    pub cursor_size: f32,
+    /// Scale of the names and chat bubbles next to the cursors.
+    pub name_size: f32,
+    /// Scale of the zoom done by the mouse wheel.
+    pub zoom_sensitivity: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            music_volume: 1.0,
+            effects_volume: 1.0,
+            music_muted: false,
+            effects_muted: false,
+            cursor_size: 1.0,
+            name_size: 1.0,
+            zoom_sensitivity: 1.0,
+        }
+    }
+}
+
+impl Settings {
+    const PREFERENCES_KEY: &'static str = "settings";
+
+    pub fn load() -> Self {
+        batbox::preferences::load(Self::PREFERENCES_KEY).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        batbox::preferences::save(Self::PREFERENCES_KEY, self);
+    }
+
+    /// Volume the music is played at, taking the mute into account.
+    pub fn music(&self) -> f64 {
+        if self.music_muted {
+            0.0
+        } else {
+            self.music_volume
+        }
+    }
+
+    /// Volume the sound effects are played at, taking the mute into account.
+    pub fn effects(&self) -> f64 {
+        if self.effects_muted {
+            0.0
+        } else {
+            self.effects_volume
+        }
+    }
+
+    /// Mutes everything, or unmutes everything if it is all muted already.
+    pub fn toggle_mute(&mut self) {
+        let mute = !(self.music_muted && self.effects_muted);
+        self.music_muted = mute;
+        self.effects_muted = mute;
+    }
+}
@@ -6,60 +6,181 @@ pub struct Slider<'a> {
     cx: &'a Controller,
     sense: &'a mut Sense,
     pos: &'a mut Option<AABB<f64>>,
+    /// Grows the handle while hovered, from 0 to 1.
+    hover: &'a mut f32,
+    text: String,
     value: f64,
     range: RangeInclusive<f64>,
+    display: Box<dyn Fn(f64) -> String + 'a>,
     change: RefCell<&'a mut Option<f64>>,
+    /// Whether the arrow keys adjust the value.
+    pub focused: &'a mut bool,
 }
 
 impl<'a> Slider<'a> {
     const ANIMATION_SPEED: f32 = 5.0;
+    /// Number of arrow key presses to go over the whole range.
+    const KEY_STEPS: f64 = 20.0;
 
     pub fn new(cx: &'a Controller, text: String, value: f64, range: RangeInclusive<f64>) -> Self {
         Slider {
             cx,
             sense: cx.get_state(),
             pos: cx.get_state(),
+            hover: cx.get_state(),
+            text,
             value,
             range,
+            display: Box::new(|value| format!("{:.2}", value)),
             change: RefCell::new(cx.get_state()),
+            focused: cx.get_state(),
+        }
+    }
+
+    /// How the value is shown next to the label.
+    pub fn display(self, display: impl Fn(f64) -> String + 'a) -> Self {
+        Self {
+            display: Box::new(display),
+            ..self
         }
     }
 
     pub fn get_change(&self) -> Option<f64> {
         self.change.borrow_mut().take()
     }
+
+    /// The line the handle moves along, below the label.
+    fn track(position: AABB<f64>) -> AABB<f64> {
+        let height = position.height() / 2.0;
+        AABB {
+            x_min: position.x_min + height / 2.0,
+            x_max: position.x_max - height / 2.0,
+            y_min: position.y_min,
+            y_max: position.y_min + height,
+        }
+    }
+
+    fn set_value(&mut self, value: f64) {
+        let value = value.clamp(*self.range.start(), *self.range.end());
+        self.value = value;
+        **self.change.borrow_mut() = Some(value);
+    }
 }
 
 impl<'a> Widget for Slider<'a> {
     fn sense(&mut self) -> Option<&mut Sense> {
         Some(self.sense)
     }
-    fn update(&mut self, delta_time: f64) {}
+    fn update(&mut self, delta_time: f64) {
+        let target = if self.sense.is_hovered() || self.sense.is_captured() || *self.focused {
+            1.0
+        } else {
+            0.0
+        };
+        let step = Self::ANIMATION_SPEED * delta_time as f32;
+        *self.hover += (target - *self.hover).clamp(-step, step);
+    }
     fn draw(&mut self, cx: &mut DrawContext) {
-        // cx.geng.draw_2d(cx.framebuffer, &geng::PixelPerfectCamera, &draw_2d::Quad::new())
+        *self.pos = Some(cx.position);
+        let font = cx.geng.default_font();
+        let position = cx.position.map(|x| x as f32);
+        let track = Self::track(cx.position).map(|x| x as f32);
+        let size = position.height() / 2.0;
+        let color = if *self.hover > 0.0 {
+            cx.theme.hover_color
+        } else {
+            cx.theme.usable_color
+        };
+
+        let baseline = position.y_max - size - font.descender() * size;
+        font.draw(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &self.text,
+            vec2(position.x_min, baseline),
+            geng::TextAlign::LEFT,
+            size,
+            color,
+        );
+        font.draw(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &(self.display)(self.value),
+            vec2(position.x_max, baseline),
+            geng::TextAlign::RIGHT,
+            size,
+            color,
+        );
+
+        let t = ((self.value - *self.range.start()) / (*self.range.end() - *self.range.start()))
+            .clamp(0.0, 1.0) as f32;
+        let handle = vec2(track.x_min + track.width() * t, track.center().y);
+        let thickness = track.height() * 0.1;
+        let mut line = |from: f32, to: f32, color: Rgba<f32>| {
+            cx.geng.draw_2d(
+                cx.framebuffer,
+                &geng::PixelPerfectCamera,
+                &draw_2d::Quad::new(
+                    AABB::from_corners(
+                        vec2(from, handle.y - thickness),
+                        vec2(to, handle.y + thickness),
+                    ),
+                    color,
+                ),
+            );
+        };
+        line(track.x_min, handle.x, color);
+        line(handle.x, track.x_max, Rgba { a: 0.5, ..color });
+        cx.geng.draw_2d(
+            cx.framebuffer,
+            &geng::PixelPerfectCamera,
+            &draw_2d::Ellipse::circle(
+                handle,
+                track.height() / 3.0 * (1.0 + *self.hover * 0.3),
+                color,
+            ),
+        );
     }
     fn handle_event(&mut self, event: &geng::Event) {
         let aabb = match *self.pos {
             Some(pos) => pos,
             None => return,
         };
+        if let geng::Event::MouseDown { .. } = event {
+            *self.focused = false;
+        }
+        if self.sense.take_clicked() {
+            *self.focused = true;
+        }
         if self.sense.is_captured() {
             if let geng::Event::MouseDown { position, .. }
             | geng::Event::MouseMove { position, .. } = &event
             {
-                let position = position.x - aabb.x_min;
-                let new_value = *self.range.start()
-                    + ((position - aabb.height() / 6.0) / (aabb.width() - aabb.height() / 3.0))
-                        .clamp(0.0, 1.0)
-                        * (*self.range.end() - *self.range.start());
-                **self.change.borrow_mut() = Some(new_value);
+                let track = Self::track(aabb);
+                let t = ((position.x - track.x_min) / track.width()).clamp(0.0, 1.0);
+                self.set_value(*self.range.start() + t * (*self.range.end() - *self.range.start()));
+            }
+        }
+        if *self.focused {
+            let step = (*self.range.end() - *self.range.start()) / Self::KEY_STEPS;
+            match event {
+                geng::Event::KeyDown {
+                    key: geng::Key::Left,
+                } => self.set_value(self.value - step),
+                geng::Event::KeyDown {
+                    key: geng::Key::Right,
+                } => self.set_value(self.value + step),
+                geng::Event::KeyDown {
+                    key: geng::Key::Escape,
+                } => *self.focused = false,
+                _ => {}
             }
         }
     }
 
     fn calc_constraints(&mut self, _children: &ConstraintsContext) -> Constraints {
         Constraints {
-            min_size: vec2(1.0, 1.0) * self.cx.theme().text_size as f64,
+            min_size: vec2(300.0, self.cx.theme().text_size as f64 * 2.0),
             flex: vec2(1.0, 0.0),
         }
     }
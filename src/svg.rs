@@ -0,0 +1,162 @@
+use super::*;
+
+use base64::Engine;
+
+/// Color and width (in mm) of the cut lines, as expected by most laser cutters.
+const CUT_COLOR: &str = "#ff0000";
+const CUT_WIDTH: f32 = 0.1;
+
+#[derive(Debug)]
+pub enum ExportError {
+    /// The room config was not given on the command line.
+    MissingConfig,
+    Io(std::io::Error),
+    Config(serde_json::Error),
+    /// The size of the puzzle image could not be read.
+    InvalidImage,
+    /// The puzzle image is in a format browsers can not show embedded in an SVG.
+    UnsupportedImage,
+    Generation(jigsaw::GenerationError),
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingConfig => write!(f, "--export-svg requires --room-config"),
+            Self::Io(error) => write!(f, "{error}"),
+            Self::Config(error) => write!(f, "invalid room config: {error}"),
+            Self::InvalidImage => write!(f, "puzzle image size could not be read"),
+            Self::UnsupportedImage => write!(f, "puzzle image format can not be embedded"),
+            Self::Generation(error) => write!(f, "failed to generate jigsaw: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<std::io::Error> for ExportError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Config(error)
+    }
+}
+
+impl From<jigsaw::GenerationError> for ExportError {
+    fn from(error: jigsaw::GenerationError) -> Self {
+        Self::Generation(error)
+    }
+}
+
+/// Reads the room config from a json file.
+pub fn load_config(path: &std::path::Path) -> Result<RoomConfig, ExportError> {
+    Ok(serde_json::from_reader(std::io::BufReader::new(
+        std::fs::File::open(path)?,
+    ))?)
+}
+
+/// Writes the cut lines of the puzzle described by the config into an SVG file.
+pub fn export(
+    config: &RoomConfig,
+    path: &std::path::Path,
+    height_mm: f32,
+    embed_image: bool,
+) -> Result<(), ExportError> {
+    let image = std::fs::read(assets::image_path(config.image))?;
+    let svg = render(config, &image, height_mm, embed_image)?;
+    std::fs::write(path, svg)?;
+    Ok(())
+}
+
+/// MIME type of the image, for the formats that can be embedded.
+fn mime_type(image: &[u8]) -> Option<&'static str> {
+    use imagesize::ImageType;
+    Some(match imagesize::image_type(image).ok()? {
+        ImageType::Png => "image/png",
+        ImageType::Jpeg => "image/jpeg",
+        ImageType::Gif => "image/gif",
+        ImageType::Webp => "image/webp",
+        ImageType::Bmp => "image/bmp",
+        _ => return None,
+    })
+}
+
+fn render(
+    config: &RoomConfig,
+    image: &[u8],
+    height_mm: f32,
+    embed_image: bool,
+) -> Result<String, ExportError> {
+    let image_size = util::image_size(image).ok_or(ExportError::InvalidImage)?;
+    // Generate with the same size as the game does so the cut is exactly the same
+    let size = jigsaw::puzzle_size(image_size);
+    let lines = jigsaw::cut_lines(config.generator_version, config.seed, size, config.size)?;
+    let scale = height_mm / size.y;
+    let svg_size = size * scale;
+
+    let mut svg = String::new();
+    svg += &format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}">"#,
+        w = svg_size.x,
+        h = svg_size.y,
+    );
+    svg += "\n";
+    if embed_image {
+        let mime_type = mime_type(image).ok_or(ExportError::UnsupportedImage)?;
+        svg += &format!(
+            r#"  <image x="0" y="0" width="{}" height="{}" preserveAspectRatio="none" href="data:{};base64,{}"/>"#,
+            svg_size.x,
+            svg_size.y,
+            mime_type,
+            base64::engine::general_purpose::STANDARD.encode(image),
+        );
+        svg += "\n";
+    }
+    svg += &format!(r#"  <g fill="none" stroke="{CUT_COLOR}" stroke-width="{CUT_WIDTH}">"#);
+    svg += "\n";
+    for line in lines {
+        let mut path = String::new();
+        for (i, v) in line.into_iter().enumerate() {
+            // Svg y axis is pointing down
+            let v = vec2(v.x, size.y - v.y) * scale;
+            path += &format!("{}{:.3} {:.3} ", if i == 0 { "M" } else { "L" }, v.x, v.y);
+        }
+        svg += &format!("    <path d=\"{}\"/>\n", path.trim_end());
+    }
+    svg += "  </g>\n</svg>\n";
+    Ok(svg)
+}
+
+#[test]
+fn test_render() {
+    let config = RoomConfig {
+        seed: 0,
+        size: vec2(3, 2),
+        image: 0,
+        generator_version: jigsaw::GENERATOR_VERSION,
+        rotation: default(),
+        double_sided: false,
+        hide_reference: false,
+        mode: default(),
+    };
+    let image = std::fs::read(assets::image_path(config.image)).unwrap();
+    let svg = render(&config, &image, 100.0, true).unwrap();
+    // The border and every edge between two pieces, each cut once
+    let edges = 2 * 2 + 3;
+    assert_eq!(svg.matches("<path ").count(), 1 + edges);
+    assert_eq!(svg.matches("<image ").count(), 1);
+    assert!(svg.contains("href=\"data:image/png;base64,"));
+}
+
+#[test]
+fn test_mime_type() {
+    let jpeg = [
+        0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F', b'I', b'F', 0, 1,
+    ];
+    assert_eq!(mime_type(&jpeg), Some("image/jpeg"));
+    assert_eq!(mime_type(b"not an image"), None);
+}
@@ -1,27 +1,351 @@
 use super::*;
 
 use geng::ui::*;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Cursor and selection of a text field, kept between frames.
+#[derive(Debug, Clone, Default)]
+pub struct TextEdit {
+    /// Byte offset of the cursor, always on a grapheme boundary.
+    cursor: usize,
+    /// Other end of the selection.
+    anchor: Option<usize>,
+}
+
+/// Modifier keys held while a key is pressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+}
+
+impl Modifiers {
+    pub fn from_window(window: &geng::Window) -> Self {
+        use geng::Key;
+        Self {
+            shift: window.is_key_pressed(Key::LShift) || window.is_key_pressed(Key::RShift),
+            ctrl: window.is_key_pressed(Key::LCtrl) || window.is_key_pressed(Key::RCtrl),
+        }
+    }
+}
+
+impl TextEdit {
+    /// Keeps the cursor valid after the text was changed from outside.
+    fn fix(&mut self, text: &str) {
+        let snap = |pos: usize| {
+            if pos >= text.len() {
+                text.len()
+            } else {
+                text.grapheme_indices(true)
+                    .map(|(index, _)| index)
+                    .take_while(|&index| index <= pos)
+                    .last()
+                    .unwrap_or(0)
+            }
+        };
+        self.cursor = snap(self.cursor);
+        self.anchor = self
+            .anchor
+            .map(snap)
+            .filter(|&anchor| anchor != self.cursor);
+    }
+
+    pub fn selection(&self) -> std::ops::Range<usize> {
+        match self.anchor {
+            Some(anchor) => anchor.min(self.cursor)..anchor.max(self.cursor),
+            None => self.cursor..self.cursor,
+        }
+    }
+
+    fn move_to(&mut self, pos: usize, select: bool) {
+        if select {
+            self.anchor.get_or_insert(self.cursor);
+        } else {
+            self.anchor = None;
+        }
+        self.cursor = pos;
+        if self.anchor == Some(pos) {
+            self.anchor = None;
+        }
+    }
+
+    fn delete_selection(&mut self, text: &mut String) -> bool {
+        let selection = self.selection();
+        if selection.is_empty() {
+            return false;
+        }
+        text.replace_range(selection.clone(), "");
+        self.cursor = selection.start;
+        self.anchor = None;
+        true
+    }
+
+    /// Replaces the selection with the string, dropping whatever doesn't fit.
+    pub fn insert(&mut self, text: &mut String, s: &str, max_len: usize) {
+        self.delete_selection(text);
+        let s: String = s.chars().filter(|c| !c.is_control()).collect();
+        let room = max_len.saturating_sub(text.graphemes(true).count());
+        let s = util::truncate_graphemes(&s, room);
+        text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    pub fn handle_key(
+        &mut self,
+        text: &mut String,
+        key: geng::Key,
+        modifiers: Modifiers,
+        max_len: usize,
+    ) {
+        use geng::Key;
+        self.fix(text);
+        match key {
+            Key::Left => {
+                let pos = match self.anchor {
+                    Some(_) if !modifiers.shift && !modifiers.ctrl => self.selection().start,
+                    _ => prev_boundary(text, self.cursor, modifiers.ctrl),
+                };
+                self.move_to(pos, modifiers.shift);
+            }
+            Key::Right => {
+                let pos = match self.anchor {
+                    Some(_) if !modifiers.shift && !modifiers.ctrl => self.selection().end,
+                    _ => next_boundary(text, self.cursor, modifiers.ctrl),
+                };
+                self.move_to(pos, modifiers.shift);
+            }
+            Key::Home => self.move_to(0, modifiers.shift),
+            Key::End => self.move_to(text.len(), modifiers.shift),
+            Key::Backspace => {
+                if !self.delete_selection(text) {
+                    let start = prev_boundary(text, self.cursor, modifiers.ctrl);
+                    text.replace_range(start..self.cursor, "");
+                    self.cursor = start;
+                }
+            }
+            Key::Delete => {
+                if !self.delete_selection(text) {
+                    let end = next_boundary(text, self.cursor, modifiers.ctrl);
+                    text.replace_range(self.cursor..end, "");
+                }
+            }
+            Key::A if modifiers.ctrl => {
+                self.anchor = Some(0);
+                self.cursor = text.len();
+                self.fix(text);
+            }
+            Key::C if modifiers.ctrl => clipboard::copy(&text[self.selection()]),
+            Key::X if modifiers.ctrl => {
+                clipboard::copy(&text[self.selection()]);
+                self.delete_selection(text);
+            }
+            Key::V if modifiers.ctrl => {
+                if let Some(pasted) = clipboard::paste() {
+                    self.insert(text, &pasted, max_len);
+                }
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            _ if !modifiers.ctrl => {
+                if let Some(c) = key_char(key, modifiers.shift) {
+                    self.insert(text, c.encode_utf8(&mut [0; 4]), max_len);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Start of the grapheme, or the word if `word`, before the position.
+fn prev_boundary(text: &str, pos: usize, word: bool) -> usize {
+    if word {
+        text[..pos]
+            .unicode_word_indices()
+            .next_back()
+            .map_or(0, |(index, _)| index)
+    } else {
+        text[..pos]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(index, _)| index)
+    }
+}
+
+/// End of the grapheme, or the word if `word`, after the position.
+fn next_boundary(text: &str, pos: usize, word: bool) -> usize {
+    if word {
+        text[pos..]
+            .unicode_word_indices()
+            .next()
+            .map_or(text.len(), |(index, word)| pos + index + word.len())
+    } else {
+        text[pos..]
+            .graphemes(true)
+            .next()
+            .map_or(pos, |grapheme| pos + grapheme.len())
+    }
+}
+
+/// Character typed by the key on a US layout.
+///
+/// Geng only reports key presses on the native window, so other layouts and
+/// non-Latin text can only be typed in the browser, which reports the typed text instead.
+#[cfg(not(target_arch = "wasm32"))]
+fn key_char(key: geng::Key, shift: bool) -> Option<char> {
+    let name = format!("{:?}", key);
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_alphabetic() {
+            return Some(if shift {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            });
+        }
+    }
+    if let Some(digit) = name
+        .strip_prefix("Num")
+        .and_then(|s| s.parse::<usize>().ok())
+    {
+        return if shift {
+            ")!@#$%^&*(".chars().nth(digit)
+        } else {
+            char::from_digit(digit as u32, 10)
+        };
+    }
+    let (normal, shifted) = match name.as_str() {
+        "Space" => (' ', ' '),
+        "Minus" => ('-', '_'),
+        "Equals" => ('=', '+'),
+        "Comma" => (',', '<'),
+        "Period" => ('.', '>'),
+        "Slash" => ('/', '?'),
+        "Semicolon" => (';', ':'),
+        "Apostrophe" => ('\'', '"'),
+        _ => return None,
+    };
+    Some(if shift { shifted } else { normal })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod clipboard {
+    use super::*;
+
+    pub fn copy(text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if let Ok(mut clipboard) = arboard::Clipboard::new() {
+            if let Err(e) = clipboard.set_text(text.to_owned()) {
+                warn!("Failed to copy text: {}", e);
+            }
+        }
+    }
+    pub fn paste() -> Option<String> {
+        arboard::Clipboard::new().ok()?.get_text().ok()
+    }
+}
+
+/// The browser clipboard can't be read synchronously,
+/// so text is only copied between fields of the game.
+#[cfg(target_arch = "wasm32")]
+mod clipboard {
+    thread_local! {
+        static CLIPBOARD: std::cell::RefCell<String> = Default::default();
+    }
+    pub fn copy(text: &str) {
+        if !text.is_empty() {
+            CLIPBOARD.with(|clipboard| *clipboard.borrow_mut() = text.to_owned());
+        }
+    }
+    pub fn paste() -> Option<String> {
+        Some(CLIPBOARD.with(|clipboard| clipboard.borrow().clone()))
+    }
+}
+
+/// Text typed in the browser, which knows the keyboard layout and input method.
+#[cfg(target_arch = "wasm32")]
+mod typed {
+    use super::*;
+
+    use wasm_bindgen::JsCast;
+
+    thread_local! {
+        static TYPED: std::cell::RefCell<Option<Vec<String>>> = Default::default();
+    }
+
+    /// Text typed since the last call.
+    pub fn take() -> Vec<String> {
+        TYPED.with(|typed| {
+            let mut typed = typed.borrow_mut();
+            if typed.is_none() {
+                listen();
+            }
+            std::mem::take(typed.get_or_insert_with(Vec::new))
+        })
+    }
+
+    fn listen() {
+        let listener = wasm_bindgen::closure::Closure::<dyn FnMut(web_sys::KeyboardEvent)>::new(
+            |event: web_sys::KeyboardEvent| {
+                // Named keys like "Backspace" are handled through the key codes
+                let key = event.key();
+                if event.ctrl_key() || event.meta_key() || key.graphemes(true).count() != 1 {
+                    return;
+                }
+                TYPED.with(|typed| typed.borrow_mut().get_or_insert_with(Vec::new).push(key));
+            },
+        );
+        web_sys::window()
+            .unwrap()
+            .add_event_listener_with_callback("keydown", listener.as_ref().unchecked_ref())
+            .unwrap();
+        listener.forget();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod typed {
+    /// Characters come from the key presses instead, see [`super::key_char`].
+    pub fn take() -> Vec<String> {
+        Vec::new()
+    }
+}
 
 pub struct TextInput<'a> {
     cx: &'a Controller,
+    window: &'a geng::Window,
     sense: &'a mut Sense,
     pos: &'a mut Option<AABB<f64>>,
     text: &'a mut String,
+    edit: &'a mut TextEdit,
     t: &'a mut f64,
     max_len: usize,
+    placeholder: &'a str,
     pub capture: &'a mut bool,
 }
 
 impl<'a> TextInput<'a> {
-    pub fn new(cx: &'a Controller, text: &'a mut String, max_len: usize) -> Self {
+    /// Text field holding at most `max_len` grapheme clusters.
+    pub fn new(cx: &'a Controller, geng: &'a Geng, text: &'a mut String, max_len: usize) -> Self {
         TextInput {
             cx,
+            window: geng.window(),
             t: cx.get_state(),
             sense: cx.get_state(),
             pos: cx.get_state(),
+            edit: cx.get_state(),
             capture: cx.get_state(),
             text,
             max_len,
+            placeholder: "",
+        }
+    }
+
+    /// Text shown while the input is empty.
+    pub fn placeholder(self, placeholder: &'a str) -> Self {
+        Self {
+            placeholder,
+            ..self
         }
     }
 }
@@ -31,57 +355,119 @@ impl<'a> Widget for TextInput<'a> {
         Some(self.sense)
     }
     fn update(&mut self, delta_time: f64) {
+        // Text typed while not focused is dropped
+        for text in typed::take() {
+            if *self.capture {
+                self.edit.insert(self.text, &text, self.max_len);
+                *self.t = 0.0;
+            }
+        }
         *self.t += delta_time;
         if *self.t > 1.0 {
             *self.t = 0.0;
         }
     }
     fn draw(&mut self, cx: &mut DrawContext) {
+        *self.pos = Some(cx.position);
+        self.edit.fix(self.text);
         let font = cx.geng.default_font();
-        let mut text = self.text.as_str();
-        if text.is_empty() {
-            if *self.capture {
-                text = "";
-            } else {
-                text = "click to change your name";
-            }
-        }
-        let _size = partial_min(
-            cx.position.height() as f32,
-            1.0 * cx.position.width() as f32
-                / font.measure(text, 1.0).map_or(0.0, |aabb| aabb.width()),
-        );
         let size = cx.position.height() as f32;
+        let width = |text: &str| font.measure(text, size).map_or(0.0, |aabb| aabb.width());
         let color = if *self.capture || self.sense.is_hovered() {
             cx.theme.hover_color
         } else {
             cx.theme.usable_color
         };
-        let w = font.measure(text, size).map_or(0.0, |aabb| aabb.width());
-        let text = if *self.t < 0.5 || !*self.capture {
-            text.to_owned()
+        let position = cx.position.map(|x| x as f32);
+        let baseline = position.center().y - size * 0.5 - font.descender() * size;
+
+        if self.text.is_empty() && !*self.capture {
+            font.draw(
+                cx.framebuffer,
+                &geng::PixelPerfectCamera,
+                self.placeholder,
+                vec2(
+                    position.center().x - width(self.placeholder) / 2.0,
+                    baseline,
+                ),
+                geng::TextAlign::LEFT,
+                size,
+                color,
+            );
+        }
+
+        // Centered while it fits, otherwise scrolled to keep the cursor visible
+        let text_width = width(self.text);
+        let cursor_x = width(&self.text[..self.edit.cursor]);
+        let x = if text_width <= position.width() {
+            position.center().x - text_width / 2.0
         } else {
-            format!("{text}_")
+            (position.x_min + position.width() - cursor_x)
+                .min(position.x_min)
+                .max(position.x_max - text_width)
         };
+        if *self.capture {
+            let selection = self.edit.selection();
+            if !selection.is_empty() {
+                cx.geng.draw_2d(
+                    cx.framebuffer,
+                    &geng::PixelPerfectCamera,
+                    &draw_2d::Quad::new(
+                        AABB::from_corners(
+                            vec2(x + width(&self.text[..selection.start]), position.y_min),
+                            vec2(x + width(&self.text[..selection.end]), position.y_max),
+                        ),
+                        Rgba { a: 0.3, ..color },
+                    ),
+                );
+            }
+        }
         font.draw(
             cx.framebuffer,
             &geng::PixelPerfectCamera,
-            &text,
-            cx.position.center().map(|x| x as f32)
-                + vec2(-w / 2.0, -size * 0.5 - font.descender() * size),
+            self.text,
+            vec2(x, baseline),
             geng::TextAlign::LEFT,
             size,
             color,
         );
+        if *self.capture && *self.t < 0.5 {
+            let x = x + cursor_x;
+            cx.geng.draw_2d(
+                cx.framebuffer,
+                &geng::PixelPerfectCamera,
+                &draw_2d::Quad::new(
+                    AABB::from_corners(
+                        vec2(x, position.y_min),
+                        vec2(x + size * 0.08, position.y_max),
+                    ),
+                    color,
+                ),
+            );
+        }
     }
     fn handle_event(&mut self, event: &geng::Event) {
+        use geng::Key;
         if let geng::Event::MouseDown { .. } = event {
             *self.capture = false;
         }
         if self.sense.take_clicked() {
             *self.capture = true;
+            self.edit.fix(self.text);
+            self.edit.move_to(self.text.len(), false);
+        }
+        if let geng::Event::KeyDown { key } = *event {
+            match key {
+                Key::Escape => *self.capture = false,
+                _ if *self.capture => {
+                    let modifiers = Modifiers::from_window(self.window);
+                    self.edit
+                        .handle_key(self.text, key, modifiers, self.max_len);
+                    *self.t = 0.0;
+                }
+                _ => {}
+            }
         }
-        // LOL
     }
 
     fn calc_constraints(&mut self, _children: &ConstraintsContext) -> Constraints {
@@ -91,3 +477,39 @@ impl<'a> Widget for TextInput<'a> {
         }
     }
 }
+
+#[test]
+fn test_text_edit() {
+    use geng::Key;
+    let mut text = String::new();
+    let mut edit = TextEdit::default();
+    edit.insert(&mut text, "héllo wörld", 15);
+    assert_eq!(edit.cursor, text.len());
+
+    let ctrl = Modifiers {
+        ctrl: true,
+        ..default()
+    };
+    edit.handle_key(&mut text, Key::Backspace, ctrl, 15);
+    assert_eq!(text, "héllo ");
+
+    let shift = Modifiers {
+        shift: true,
+        ..default()
+    };
+    edit.handle_key(&mut text, Key::Left, shift, 15);
+    edit.handle_key(&mut text, Key::Left, shift, 15);
+    edit.handle_key(&mut text, Key::Left, shift, 15);
+    assert_eq!(&text[edit.selection()], "lo ");
+    edit.insert(&mut text, "\u{1F44D}\u{1F3FD}!", 15);
+    assert_eq!(text, "hél\u{1F44D}\u{1F3FD}!");
+
+    // Length is counted in grapheme clusters, so the thumbs up is one
+    edit.handle_key(&mut text, Key::Home, default(), 15);
+    edit.insert(&mut text, "0123456789abcdef", 15);
+    assert_eq!(text, "0123456789hél\u{1F44D}\u{1F3FD}!");
+    edit.handle_key(&mut text, Key::End, default(), 15);
+    edit.handle_key(&mut text, Key::Backspace, default(), 15);
+    edit.handle_key(&mut text, Key::Backspace, default(), 15);
+    assert_eq!(text, "0123456789hél");
+}
@@ -1,5 +1,7 @@
 use super::*;
 
+use unicode_segmentation::UnicodeSegmentation;
+
 pub fn triangle_contains(tri: [Vec2<f32>; 3], pos: Vec2<f32>) -> bool {
     let d0 = line_signed_d(pos, tri[0], tri[1]);
     let d1 = line_signed_d(pos, tri[1], tri[2]);
@@ -14,3 +16,85 @@ pub fn triangle_contains(tri: [Vec2<f32>; 3], pos: Vec2<f32>) -> bool {
 pub fn line_signed_d(p0: Vec2<f32>, p1: Vec2<f32>, p2: Vec2<f32>) -> f32 {
     (p0.x - p2.x) * (p1.y - p2.y) - (p1.x - p2.x) * (p0.y - p2.y)
 }
+
+/// Whether segments `a-b` and `c-d` properly cross each other.
+pub fn segments_intersect(a: Vec2<f32>, b: Vec2<f32>, c: Vec2<f32>, d: Vec2<f32>) -> bool {
+    let d0 = line_signed_d(c, a, b);
+    let d1 = line_signed_d(d, a, b);
+    let d2 = line_signed_d(a, c, d);
+    let d3 = line_signed_d(b, c, d);
+    d0 * d1 < 0.0 && d2 * d3 < 0.0
+}
+
+pub fn point_segment_distance(p: Vec2<f32>, a: Vec2<f32>, b: Vec2<f32>) -> f32 {
+    let ab = b - a;
+    let len_sqr = Vec2::dot(ab, ab);
+    if len_sqr == 0.0 {
+        return (p - a).len();
+    }
+    let t = (Vec2::dot(p - a, ab) / len_sqr).clamp(0.0, 1.0);
+    (p - (a + ab * t)).len()
+}
+
+pub fn segment_distance(a: Vec2<f32>, b: Vec2<f32>, c: Vec2<f32>, d: Vec2<f32>) -> f32 {
+    if segments_intersect(a, b, c, d) {
+        return 0.0;
+    }
+    [
+        point_segment_distance(a, c, d),
+        point_segment_distance(b, c, d),
+        point_segment_distance(c, a, b),
+        point_segment_distance(d, a, b),
+    ]
+    .into_iter()
+    .fold(f32::INFINITY, f32::min)
+}
+
+/// Reads image dimensions from the file header, without decoding the image.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn image_size(data: &[u8]) -> Option<Vec2<usize>> {
+    let size = imagesize::blob_size(data).ok()?;
+    Some(vec2(size.width, size.height))
+}
+
+/// Formats seconds as `m:ss`, or `h:mm:ss` from an hour on.
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    if seconds >= 60 * 60 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// Cuts the text to at most `max_len` grapheme clusters.
+pub fn truncate_graphemes(text: &str, max_len: usize) -> &str {
+    match text.grapheme_indices(true).nth(max_len) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+/// Smallest box containing all the points, `None` if there are none.
+pub fn bounding_box(points: impl IntoIterator<Item = Vec2<f32>>) -> Option<AABB<f32>> {
+    points.into_iter().fold(None, |aabb, p| {
+        Some(match aabb {
+            None => AABB::point(p),
+            Some(aabb) => aabb_union(aabb, AABB::point(p)),
+        })
+    })
+}
+
+pub fn aabb_union(a: AABB<f32>, b: AABB<f32>) -> AABB<f32> {
+    AABB {
+        x_min: a.x_min.min(b.x_min),
+        x_max: a.x_max.max(b.x_max),
+        y_min: a.y_min.min(b.y_min),
+        y_max: a.y_max.max(b.y_max),
+    }
+}